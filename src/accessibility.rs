@@ -0,0 +1,174 @@
+// 色の見分けにくさへの対応
+//
+// テーマの上にかぶせるもので、テーマファイルはそのまま使える
+//   palette : 色覚の型ごとに見分けやすい色に置き換える(Okabe-Itoの配色をもとにした)
+//   shapes  : ロボットやジャンクを形で描き分け、レーザーを破線にする
+//   outline : ものの周りに背景と逆の明るさの枠を描く
+// 画面で切り替えられて、settings.txtに保存する
+
+use crate::theme::{Look, Rgb, Shape, Theme};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum Palette {
+    // テーマの色のまま
+    #[default]
+    Normal,
+    Deuteranopia,
+    Protanopia,
+    Tritanopia,
+}
+
+pub const PALETTES: [Palette; 4] = [
+    Palette::Normal,
+    Palette::Deuteranopia,
+    Palette::Protanopia,
+    Palette::Tritanopia,
+];
+
+impl Palette {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Palette::Normal => "normal",
+            Palette::Deuteranopia => "deuteranopia",
+            Palette::Protanopia => "protanopia",
+            Palette::Tritanopia => "tritanopia",
+        }
+    }
+
+    pub fn next(&self) -> Palette {
+        let index = PALETTES.iter().position(|p| p == self).unwrap();
+        PALETTES[(index + 1) % PALETTES.len()]
+    }
+
+    // 赤と緑が見分けにくい型は青と橙の差と明るさの差で、青と黄が見分けにくい型は赤と水色の差で分ける
+    fn apply(&self, theme: &mut Theme) {
+        let (robot, frozen, remembered, junk, laser, explosion, game_over) = match self {
+            Palette::Normal => return,
+            Palette::Deuteranopia => (
+                Rgb(230, 159, 0),
+                Rgb(86, 180, 233),
+                Rgb(115, 80, 0),
+                Rgb(96, 96, 96),
+                Rgb(86, 180, 233),
+                Rgb(240, 228, 66),
+                Rgb(0, 114, 178),
+            ),
+            // 赤が暗く見えるので、ロボットは明るい黄色にする
+            Palette::Protanopia => (
+                Rgb(240, 228, 66),
+                Rgb(0, 114, 178),
+                Rgb(120, 114, 33),
+                Rgb(96, 96, 96),
+                Rgb(86, 180, 233),
+                Rgb(230, 159, 0),
+                Rgb(0, 114, 178),
+            ),
+            Palette::Tritanopia => (
+                Rgb(213, 94, 0),
+                Rgb(0, 158, 115),
+                Rgb(107, 47, 0),
+                Rgb(112, 112, 112),
+                Rgb(255, 255, 255),
+                Rgb(204, 121, 167),
+                Rgb(0, 0, 0),
+            ),
+        };
+        theme.robot = robot;
+        theme.robot_frozen = frozen;
+        theme.robot_remembered = remembered;
+        theme.junk = junk;
+        theme.laser = laser;
+        theme.explosion = explosion;
+        theme.game_over = game_over;
+        // 火花は爆発と、焦げ跡はレーザーと同じ系統の色にする。破片は明るさだけで見せる
+        theme.spark = explosion;
+        theme.debris = Rgb(160, 160, 160);
+        theme.scorch = Rgb(laser.0 / 2, laser.1 / 2, laser.2 / 2);
+        // お知らせは明るさの差だけで読めるようにする
+        theme.toast = Rgb(0, 0, 0);
+        theme.toast_title = Rgb(255, 255, 255);
+        theme.toast_text = Rgb(192, 192, 192);
+        theme.cannon = Rgb(255, 255, 255);
+        theme.players = [Rgb(255, 255, 255), Rgb(204, 121, 167)];
+        theme.win = match self {
+            Palette::Tritanopia => vec![
+                Rgb(213, 94, 0),
+                Rgb(255, 255, 255),
+                Rgb(0, 158, 115),
+                Rgb(204, 121, 167),
+            ],
+            _ => vec![
+                Rgb(230, 159, 0),
+                Rgb(255, 255, 255),
+                Rgb(86, 180, 233),
+                Rgb(240, 228, 66),
+            ],
+        };
+        theme.items = match self {
+            Palette::Tritanopia => [
+                Rgb(255, 255, 255),
+                Rgb(0, 158, 115),
+                Rgb(160, 100, 60),
+                Rgb(128, 230, 230),
+                Rgb(204, 121, 167),
+            ],
+            _ => [
+                Rgb(240, 228, 66),
+                Rgb(0, 114, 178),
+                Rgb(160, 120, 80),
+                Rgb(86, 180, 233),
+                Rgb(204, 121, 167),
+            ],
+        };
+    }
+}
+
+impl std::str::FromStr for Palette {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PALETTES
+            .iter()
+            .copied()
+            .find(|p| p.name() == s)
+            .ok_or_else(|| format!("unknown palette: {}", s))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Accessibility {
+    pub palette: Palette,
+    pub shapes: bool,
+    pub outline: bool,
+}
+
+impl Accessibility {
+    // テーマに設定をかぶせたものを返す
+    pub fn apply(&self, theme: &Theme) -> Theme {
+        let mut theme = theme.clone();
+        self.palette.apply(&mut theme);
+        if self.shapes {
+            // 画像や文字はもともと形で見分けられるのでそのままにする
+            let shape = |look: &Look, shape| match look {
+                Look::Cell | Look::Shape(_) => Look::Shape(shape),
+                look => look.clone(),
+            };
+            theme.robot_look = Look::Shape(Shape::Diamond);
+            theme.junk_look = Look::Shape(Shape::Cross);
+            theme.player_look = shape(&theme.player_look, Shape::Circle);
+            theme.cannon_look = shape(&theme.cannon_look, Shape::Triangle);
+            theme.laser_dashed = true;
+        }
+        if self.outline {
+            // 暗い背景には白、明るい背景には黒の枠
+            let Rgb(r, g, b) = theme.background;
+            let light = r as u32 * 299 + g as u32 * 587 + b as u32 * 114 > 128 * 1000;
+            theme.outline = Some(if light {
+                Rgb(0, 0, 0)
+            } else {
+                Rgb(255, 255, 255)
+            });
+        }
+        theme
+    }
+}
@@ -0,0 +1,206 @@
+// 描画のアニメーション
+//
+// コマンドで動いたものは、モデルが覚えているprev_posから今の位置までtween_framesフレームかけて動かして見せる
+// テレポートは元の位置で消えて行き先に現れ、壊れたロボットは動き終わってから爆発する
+// レーザーは砲台から伸びてから消えていく。tween_framesが0ならアニメーションはしない
+// スプライトシートのアニメーションはclockで進め、壊れたロボットと死んだプレイヤーはdeathsで見せる
+//
+// アニメーションの間に押されたキーは画面側でためておき、終わってから1つずつ処理する
+// ためているキーが多いほど速く動かすので、速く打っても遅れはたまらない
+
+use crate::model::*;
+
+pub const TWEEN_FRAMES_DEFAULT: u32 = 4;
+pub const EXPLOSION_FRAMES: i32 = 10;
+pub const LASER_FADE_FRAMES: i32 = 12;
+// 壊れたロボットや死んだプレイヤーのdeathアニメーションを見せるフレーム数
+pub const DEATH_FRAMES: i32 = FPS;
+
+pub struct Explosion {
+    pub pos: Vec2,
+    // 動き終わるまでは負
+    pub age: i32,
+    // ロボット同士がぶつかってジャンクになったときは大きくする
+    pub big: bool,
+}
+
+pub struct Death {
+    pub pos: Vec2,
+    // 動き終わるまでは負
+    pub age: i32,
+    // 死んだプレイヤー。ロボットならNone
+    pub player: Option<usize>,
+}
+
+impl Death {
+    // 見せ始めてからのミリ秒
+    pub fn ms(&self) -> u32 {
+        (self.age.max(0) * 1000 / FPS) as u32
+    }
+}
+
+pub struct Animator {
+    tween_frames: i32,
+    frame: i32,
+    motion: u64,
+    // (プレイヤー, 元の位置, 行き先)
+    teleports: Vec<(usize, Vec2, Vec2)>,
+    // 壊れたロボットの(動き始めた位置, 壊れた位置)
+    wrecks: Vec<(Vec2, Vec2)>,
+    pub explosions: Vec<Explosion>,
+    pub deaths: Vec<Death>,
+    // 始まってからのフレーム数
+    clock: u32,
+    // レーザーを撃ってからのフレーム数
+    laser_frame: Option<i32>,
+}
+
+impl Animator {
+    pub fn new(tween_frames: u32) -> Self {
+        Animator {
+            tween_frames: tween_frames as i32,
+            frame: 0,
+            motion: 0,
+            teleports: Vec::new(),
+            wrecks: Vec::new(),
+            explosions: Vec::new(),
+            deaths: Vec::new(),
+            clock: 0,
+            laser_frame: None,
+        }
+    }
+
+    // 盤面が動いていたら、動きを最初から見せる
+    pub fn observe(&mut self, game: &Game) {
+        if game.motion == self.motion {
+            return;
+        }
+        self.motion = game.motion;
+        self.frame = 0;
+        self.teleports.clear();
+        self.wrecks.clear();
+    }
+
+    pub fn handle_event(&mut self, event: &GameEvent) {
+        if self.tween_frames <= 0 {
+            return;
+        }
+        match event {
+            GameEvent::Teleported { player, from, to } => {
+                self.teleports.push((*player, *from, *to));
+            }
+            GameEvent::RobotDestroyed {
+                pos, cause, from, ..
+            } => {
+                self.wrecks.push((*from, *pos));
+                self.explosions.push(Explosion {
+                    pos: *pos,
+                    age: -1,
+                    big: *cause == KillCause::Collision,
+                });
+                self.deaths.push(Death {
+                    pos: *pos,
+                    age: -1,
+                    player: None,
+                });
+            }
+            GameEvent::PlayerDied { player, pos, .. } => {
+                self.deaths.push(Death {
+                    pos: *pos,
+                    age: -1,
+                    player: Some(*player),
+                });
+            }
+            GameEvent::LaserFired { .. } => self.laser_frame = Some(0),
+            _ => {}
+        }
+    }
+
+    // 1フレーム進める。speedはためているキーの数に合わせて大きくする
+    pub fn advance(&mut self, speed: i32) {
+        self.frame = (self.frame + speed.max(1)).min(self.tween_frames);
+        let busy = self.is_busy();
+        for explosion in &mut self.explosions {
+            if explosion.age >= 0 {
+                explosion.age += 1;
+            } else if !busy {
+                explosion.age = 0;
+            }
+        }
+        self.explosions.retain(|e| e.age < EXPLOSION_FRAMES);
+        for death in &mut self.deaths {
+            if death.age >= 0 {
+                death.age += 1;
+            } else if !busy {
+                death.age = 0;
+            }
+        }
+        self.deaths.retain(|d| d.age < DEATH_FRAMES);
+        self.clock = self.clock.wrapping_add(1);
+        if let Some(frame) = &mut self.laser_frame {
+            *frame += 1;
+        }
+    }
+
+    // 始まってからのミリ秒。止まっているときのアニメーションに使う
+    pub fn ms(&self) -> u32 {
+        (self.clock as u64 * 1000 / FPS as u64) as u32
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.frame < self.tween_frames
+    }
+
+    // 動き終わるまでのフレーム数
+    pub fn remaining(&self) -> i32 {
+        self.tween_frames - self.frame
+    }
+
+    // 0.0から1.0まで。動き始めと終わりをゆっくりにする
+    pub fn progress(&self) -> f64 {
+        if self.tween_frames <= 0 {
+            return 1.0;
+        }
+        let t = self.frame as f64 / self.tween_frames as f64;
+        t * t * (3.0 - 2.0 * t)
+    }
+
+    // テレポート中ならその元の位置と行き先
+    pub fn teleport(&self, player: usize) -> Option<(Vec2, Vec2)> {
+        if !self.is_busy() {
+            return None;
+        }
+        self.teleports
+            .iter()
+            .find(|(p, _, _)| *p == player)
+            .map(|(_, from, to)| (*from, *to))
+    }
+
+    // 壊れる前に動いているロボット
+    pub fn wrecks(&self) -> &[(Vec2, Vec2)] {
+        if self.is_busy() {
+            &self.wrecks
+        } else {
+            &[]
+        }
+    }
+
+    // ぶつかったロボットが動き終わるまでは、そこにできたジャンクを描かない
+    pub fn hides_junk(&self, pos: Vec2) -> bool {
+        self.explosions
+            .iter()
+            .any(|e| e.big && e.age < 0 && e.pos == pos)
+    }
+
+    // レーザーの(砲台から伸びた割合, 濃さ)
+    pub fn laser(&self) -> (f64, u8) {
+        let Some(frame) = self.laser_frame.filter(|_| self.tween_frames > 0) else {
+            return (1.0, 255);
+        };
+        if frame < self.tween_frames {
+            return ((frame + 1) as f64 / self.tween_frames as f64, 255);
+        }
+        let fade = (frame - self.tween_frames) as f64 / LASER_FADE_FRAMES as f64;
+        (1.0, (255.0 * (1.0 - fade)).clamp(0.0, 255.0) as u8)
+    }
+}
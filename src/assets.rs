@@ -0,0 +1,216 @@
+// 画像・音・フォントの置き場所
+//
+// ゲームに要るものはASSET_MANIFESTに並べて、バイナリに埋め込んでおく。どこから起動しても動く
+// 同じ名前のファイルが見つかればそちらを使う。探す順は
+//   --assets DIR
+//   $XDG_DATA_HOME/rust-robots/assets
+//   ./resources (リポジトリの中で起動したとき)
+//   実行ファイルと同じ場所のresources
+// それぞれのimage/ sound/ font/ を見る。読めないファイルは警告を出して次を試す
+// 画像はBMPかPNGで、コマとアニメーションを決めるスプライトシート(.sheet)も画像と同じimage/に置く
+// 遊んでいる間もAssetWatcherでファイルの更新時刻を見て、書き換えられたものを読み直す
+
+use crate::storage;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AssetKind {
+    Image,
+    Sound,
+    Font,
+    Sheet,
+}
+
+pub const ASSET_KINDS: [AssetKind; 4] = [
+    AssetKind::Image,
+    AssetKind::Sound,
+    AssetKind::Font,
+    AssetKind::Sheet,
+];
+
+impl AssetKind {
+    fn dir(&self) -> &'static str {
+        match self {
+            AssetKind::Image => "image",
+            AssetKind::Sound => "sound",
+            AssetKind::Font => "font",
+            AssetKind::Sheet => "image",
+        }
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            AssetKind::Image => &["bmp", "png"],
+            AssetKind::Sound => &["wav"],
+            AssetKind::Font => &["ttf"],
+            AssetKind::Sheet => &["sheet"],
+        }
+    }
+}
+
+pub struct Asset {
+    pub kind: AssetKind,
+    pub name: &'static str,
+    pub data: &'static [u8],
+}
+
+// テーマにフォントがないときに使う
+pub const DEFAULT_FONT: &str = "boxfont2.ttf";
+
+macro_rules! asset {
+    ($kind:ident, $dir:literal, $name:literal) => {
+        Asset {
+            kind: AssetKind::$kind,
+            name: $name,
+            data: include_bytes!(concat!("../resources/", $dir, "/", $name)),
+        }
+    };
+}
+
+// ゲームが使う画像・音・フォント
+pub const ASSET_MANIFEST: [Asset; 17] = [
+    asset!(Image, "image", "hito.bmp"),
+    asset!(Image, "image", "laser_cannon.bmp"),
+    asset!(Image, "image", "player.png"),
+    asset!(Image, "image", "robot.png"),
+    asset!(Image, "image", "cannon.png"),
+    asset!(Sheet, "image", "hito.sheet"),
+    asset!(Sheet, "image", "player.sheet"),
+    asset!(Sheet, "image", "robot.sheet"),
+    asset!(Sheet, "image", "cannon.sheet"),
+    asset!(Sound, "sound", "bravo.wav"),
+    asset!(Sound, "sound", "crash.wav"),
+    asset!(Sound, "sound", "hit.wav"),
+    asset!(Sound, "sound", "laser.wav"),
+    asset!(Sound, "sound", "ng.wav"),
+    asset!(Sound, "sound", "pickup.wav"),
+    asset!(Sound, "sound", "shoot.wav"),
+    asset!(Font, "font", "boxfont2.ttf"),
+];
+
+pub enum AssetSource {
+    File(PathBuf),
+    Embedded(&'static [u8]),
+}
+
+impl fmt::Display for AssetSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssetSource::File(path) => write!(f, "{}", path.display()),
+            AssetSource::Embedded(_) => write!(f, "embedded"),
+        }
+    }
+}
+
+pub struct Assets {
+    // 探すディレクトリ。先にあるほうを使う
+    dirs: Vec<PathBuf>,
+}
+
+impl Assets {
+    pub fn new(override_dir: Option<&Path>) -> Self {
+        let mut dirs: Vec<PathBuf> = override_dir.into_iter().map(PathBuf::from).collect();
+        dirs.push(storage::data_dir().join("assets"));
+        dirs.push(PathBuf::from("resources"));
+        if let Some(dir) = std::env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(|p| p.join("resources")))
+        {
+            dirs.push(dir);
+        }
+        Assets { dirs }
+    }
+
+    // nameを読める候補。ファイルを探す順に並べて、最後に埋め込んだもの
+    pub fn sources(&self, kind: AssetKind, name: &str) -> Vec<AssetSource> {
+        let mut sources: Vec<AssetSource> = self
+            .dirs
+            .iter()
+            .map(|dir| dir.join(kind.dir()).join(name))
+            .filter(|path| path.is_file())
+            .map(AssetSource::File)
+            .collect();
+        if let Some(asset) = ASSET_MANIFEST
+            .iter()
+            .find(|a| a.kind == kind && a.name == name)
+        {
+            sources.push(AssetSource::Embedded(asset.data));
+        }
+        sources
+    }
+
+    // 使えるものの名前。マニフェストにあるものと、探すディレクトリに置かれたもの
+    pub fn names(&self, kind: AssetKind) -> Vec<String> {
+        let mut names: Vec<String> = ASSET_MANIFEST
+            .iter()
+            .filter(|a| a.kind == kind)
+            .map(|a| a.name.to_string())
+            .collect();
+        names.extend(self.files(kind).into_iter().map(|(name, _)| name));
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    // 探すディレクトリに置かれたファイルの(名前, パス)
+    fn files(&self, kind: AssetKind) -> Vec<(String, PathBuf)> {
+        let mut files = Vec::new();
+        for dir in &self.dirs {
+            let Ok(entries) = fs::read_dir(dir.join(kind.dir())) else {
+                continue;
+            };
+            for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+                let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+                if kind.extensions().contains(&extension) {
+                    if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+                        files.push((name.to_string(), path.clone()));
+                    }
+                }
+            }
+        }
+        files
+    }
+}
+
+// ファイルの更新時刻を覚えておき、変わったものを探す
+#[derive(Default)]
+pub struct AssetWatcher {
+    stamps: HashMap<PathBuf, (AssetKind, String, Option<SystemTime>)>,
+    started: bool,
+}
+
+impl AssetWatcher {
+    // 前に呼んだときから増えたり、書き換えられたり、消えたりしたもの。最初の呼び出しでは覚えるだけ
+    pub fn poll(&mut self, assets: &Assets) -> Vec<(AssetKind, String)> {
+        let mut stamps = HashMap::new();
+        for kind in ASSET_KINDS {
+            for (name, path) in assets.files(kind) {
+                let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+                stamps.insert(path, (kind, name, modified));
+            }
+        }
+        let mut changed = Vec::new();
+        if self.started {
+            for (path, (kind, name, modified)) in &stamps {
+                if self.stamps.get(path).map(|s| s.2) != Some(*modified) {
+                    changed.push((*kind, name.clone()));
+                }
+            }
+            // 消えたものは次の候補から読み直す
+            for (path, (kind, name, _)) in &self.stamps {
+                if !stamps.contains_key(path) {
+                    changed.push((*kind, name.clone()));
+                }
+            }
+        }
+        changed.sort_by(|a, b| a.1.cmp(&b.1));
+        changed.dedup();
+        self.stamps = stamps;
+        self.started = true;
+        changed
+    }
+}
@@ -0,0 +1,219 @@
+// まとめて描くための仕組み
+//
+// 盤面が大きいとロボットやジャンクを何千も描くので、描く回数とテクスチャの作り直しを減らす
+//   Atlas     : 画像とテーマの文字を1枚のテクスチャに並べる。同じテクスチャから続けて描ける
+//   Batch     : 色で塗るものをためて、色ごとに1回のfill_rectsで描く
+//   TextCache : 文字列のテクスチャを(文字列, 色)ごとにとっておき、使われなくなったものだけ消す
+
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::render::{BlendMode, Canvas, Texture, TextureCreator};
+use sdl2::ttf::Font;
+use sdl2::video::{Window, WindowContext};
+use std::collections::HashMap;
+
+// アトラスの幅。これを超えたら次の段に並べる
+const ATLAS_WIDTH_MAX: u32 = 1024;
+// にじまないように画像の間をあける
+const ATLAS_PADDING: u32 = 1;
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub enum SpriteKey {
+    // resources/image/のファイル名
+    Image(String),
+    // テーマで文字で描くもの
+    Glyph(char),
+}
+
+pub struct Atlas<'a> {
+    pub texture: Texture<'a>,
+    images: HashMap<String, Rect>,
+    glyphs: HashMap<char, Rect>,
+}
+
+impl<'a> Atlas<'a> {
+    // spritesをアトラスに写す。元のテクスチャはそのまま残る
+    pub fn build(
+        canvas: &mut Canvas<Window>,
+        texture_creator: &'a TextureCreator<WindowContext>,
+        sprites: Vec<(SpriteKey, &mut Texture)>,
+    ) -> Result<Atlas<'a>, String> {
+        let sizes: Vec<(u32, u32)> = sprites
+            .iter()
+            .map(|(_, t)| {
+                let q = t.query();
+                (q.width, q.height)
+            })
+            .collect();
+        let (places, width, height) = pack(&sizes, ATLAS_WIDTH_MAX);
+        let mut texture = texture_creator
+            .create_texture_target(PixelFormatEnum::RGBA8888, width.max(1), height.max(1))
+            .map_err(|e| e.to_string())?;
+        texture.set_blend_mode(BlendMode::Blend);
+
+        let mut images = HashMap::new();
+        let mut glyphs = HashMap::new();
+        let mut result = Ok(());
+        canvas
+            .with_texture_canvas(&mut texture, |target| {
+                target.set_draw_color(Color::RGBA(0, 0, 0, 0));
+                target.clear();
+                for ((key, source), ((x, y), (w, h))) in
+                    sprites.into_iter().zip(places.into_iter().zip(sizes))
+                {
+                    let rect = Rect::new(x, y, w, h);
+                    // 透明な部分もそのまま写す
+                    source.set_blend_mode(BlendMode::None);
+                    if let Err(e) = target.copy(source, None, rect) {
+                        result = Err(e);
+                    }
+                    source.set_blend_mode(BlendMode::Blend);
+                    match key {
+                        SpriteKey::Image(name) => images.insert(name, rect),
+                        SpriteKey::Glyph(c) => glyphs.insert(c, rect),
+                    };
+                }
+            })
+            .map_err(|e| e.to_string())?;
+        result?;
+        Ok(Atlas {
+            texture,
+            images,
+            glyphs,
+        })
+    }
+
+    // アトラスの中の場所
+    pub fn image(&self, name: &str) -> Option<Rect> {
+        self.images.get(name).copied()
+    }
+
+    pub fn glyph(&self, c: char) -> Option<Rect> {
+        self.glyphs.get(&c).copied()
+    }
+}
+
+// 左から並べて、幅がmax_widthを超えたら次の段にする。(それぞれの位置, 全体の幅, 全体の高さ)
+fn pack(sizes: &[(u32, u32)], max_width: u32) -> (Vec<(i32, i32)>, u32, u32) {
+    let mut places = Vec::new();
+    let (mut x, mut y, mut row_height, mut width) = (0, 0, 0, 0);
+    for &(w, h) in sizes {
+        if x > 0 && x + w > max_width {
+            x = 0;
+            y += row_height + ATLAS_PADDING;
+            row_height = 0;
+        }
+        places.push((x as i32, y as i32));
+        x += w + ATLAS_PADDING;
+        row_height = row_height.max(h);
+        width = width.max(x);
+    }
+    (places, width, y + row_height)
+}
+
+// 色ごとの矩形。flushするまで描かない
+#[derive(Default)]
+pub struct Batch {
+    fills: Vec<(Color, Vec<Rect>)>,
+    frames: Vec<(Color, Vec<Rect>)>,
+}
+
+impl Batch {
+    pub fn fill(&mut self, color: Color, rects: impl IntoIterator<Item = Rect>) {
+        add(&mut self.fills, color, rects);
+    }
+
+    pub fn frame(&mut self, color: Color, rect: Rect) {
+        add(&mut self.frames, color, [rect]);
+    }
+
+    // 枠を先に描き、中身はその内側に塗る
+    pub fn flush(&mut self, canvas: &mut Canvas<Window>) -> Result<(), String> {
+        for (color, rects) in self.frames.drain(..) {
+            canvas.set_draw_color(color);
+            canvas.draw_rects(&rects)?;
+        }
+        for (color, rects) in self.fills.drain(..) {
+            canvas.set_draw_color(color);
+            canvas.fill_rects(&rects)?;
+        }
+        Ok(())
+    }
+}
+
+// 色の種類は少ないので順に探す
+fn add(list: &mut Vec<(Color, Vec<Rect>)>, color: Color, rects: impl IntoIterator<Item = Rect>) {
+    match list.iter_mut().find(|(c, _)| *c == color) {
+        Some((_, list)) => list.extend(rects),
+        None => list.push((color, rects.into_iter().collect())),
+    }
+}
+
+struct CachedText<'a> {
+    texture: Texture<'a>,
+    // このフレームで使ったか
+    used: bool,
+}
+
+pub struct TextCache<'a> {
+    texture_creator: &'a TextureCreator<WindowContext>,
+    font: Option<Font<'a, 'a>>,
+    entries: HashMap<Color, HashMap<String, CachedText<'a>>>,
+}
+
+impl<'a> TextCache<'a> {
+    pub fn new(texture_creator: &'a TextureCreator<WindowContext>) -> Self {
+        TextCache {
+            texture_creator,
+            font: None,
+            entries: HashMap::new(),
+        }
+    }
+
+    // フォントが変わったら作ったテクスチャは使えない
+    pub fn set_font(&mut self, font: Font<'a, 'a>) {
+        self.font = Some(font);
+        self.entries.clear();
+    }
+
+    // textをcolorで描いたテクスチャ。なければ作る
+    // フェードする文字のために、透明度はテクスチャを作り直さずにalpha modで付ける
+    pub fn get(&mut self, text: &str, color: Color) -> Result<&Texture<'a>, String> {
+        let opaque = Color::RGB(color.r, color.g, color.b);
+        let entries = self.entries.entry(opaque).or_default();
+        if !entries.contains_key(text) {
+            let font = self.font.as_ref().ok_or("no font loaded")?;
+            let surface = font
+                .render(text)
+                .blended(opaque)
+                .map_err(|e| e.to_string())?;
+            let texture = self
+                .texture_creator
+                .create_texture_from_surface(&surface)
+                .map_err(|e| e.to_string())?;
+            entries.insert(
+                text.to_string(),
+                CachedText {
+                    texture,
+                    used: false,
+                },
+            );
+        }
+        let entry = entries.get_mut(text).unwrap();
+        entry.used = true;
+        entry.texture.set_alpha_mod(color.a);
+        Ok(&entry.texture)
+    }
+
+    // フレームの終わりに、使わなかった文字列を消す
+    pub fn end_frame(&mut self) {
+        for entries in self.entries.values_mut() {
+            entries.retain(|_, e| std::mem::take(&mut e.used));
+        }
+        self.entries.retain(|_, entries| !entries.is_empty());
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.values().map(|e| e.len()).sum()
+    }
+}
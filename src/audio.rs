@@ -0,0 +1,156 @@
+// 効果音
+//
+// モデルが1フレームの間に頼んだ音をまとめて鳴らす
+//   同じ音は1回だけ鳴らす。ロボットが30台ぶつかってもhit.wavは1回
+//   CHANNELS個のチャンネルで重ねて鳴らす。空きがなければ優先度の低い音を止めて、大事な音を鳴らす
+//   音量は全体(master)と効果音(sfx)を掛けたもので、ミュートもできる。settings.txtに保存する
+
+use sdl2::mixer::{self, Channel, Chunk};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+// 同時に鳴らせる音の数
+pub const CHANNELS: usize = 16;
+// キー1回で変える音量(%)
+pub const VOLUME_STEP: u8 = 10;
+
+// 大きいほど優先する
+fn priority(name: &str) -> u8 {
+    match name {
+        // プレイヤーが死んだとき、レベルをクリアしたとき
+        "crash.wav" | "bravo.wav" => 2,
+        // 受け付けなかった操作
+        "ng.wav" => 0,
+        _ => 1,
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Volume {
+    // 0から100(%)
+    pub master: u8,
+    pub sfx: u8,
+    pub muted: bool,
+}
+
+impl Default for Volume {
+    fn default() -> Self {
+        Volume {
+            master: 100,
+            sfx: 100,
+            muted: false,
+        }
+    }
+}
+
+impl Volume {
+    // SDL_mixerの音量。0からMAX_VOLUMEまで
+    fn mixer_volume(&self) -> i32 {
+        if self.muted {
+            return 0;
+        }
+        mixer::MAX_VOLUME * self.master as i32 * self.sfx as i32 / 10_000
+    }
+
+    // 画面に出す1行
+    pub fn summary(&self) -> String {
+        if self.muted {
+            return "MUTED".to_string();
+        }
+        format!("MASTER {}%  SFX {}%", self.master, self.sfx)
+    }
+}
+
+// VOLUME_STEPずつ上げ下げする。0から100に収める
+pub fn step_volume(volume: u8, up: bool) -> u8 {
+    if up {
+        volume.saturating_add(VOLUME_STEP).min(100)
+    } else {
+        volume.saturating_sub(VOLUME_STEP)
+    }
+}
+
+pub struct Audio {
+    // 音が出せるか
+    enabled: bool,
+    volume: Volume,
+    // チャンネルごとの、鳴らした音の優先度
+    priorities: [u8; CHANNELS],
+}
+
+impl Audio {
+    // enabledはmixerを開けたか
+    pub fn new(enabled: bool, volume: Volume) -> Self {
+        if enabled {
+            mixer::allocate_channels(CHANNELS as i32);
+        }
+        let mut audio = Audio {
+            enabled,
+            volume,
+            priorities: [0; CHANNELS],
+        };
+        audio.set_volume(volume);
+        audio
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn volume(&self) -> Volume {
+        self.volume
+    }
+
+    // 鳴っている音にもすぐに効く
+    pub fn set_volume(&mut self, volume: Volume) {
+        self.volume = volume;
+        if self.enabled {
+            Channel::all().set_volume(volume.mixer_volume());
+        }
+    }
+
+    // requestedの音を鳴らす。優先度の高いものから、同じ優先度なら頼まれた順
+    // 読めなかった音は鳴らさない。警告は読み込んだときに出している
+    pub fn play(&mut self, requested: &[&str], chunks: &HashMap<String, Chunk>) {
+        if !self.enabled || self.volume.muted {
+            return;
+        }
+        let mut names: Vec<&str> = Vec::new();
+        for name in requested {
+            if !names.contains(name) {
+                names.push(name);
+            }
+        }
+        names.sort_by_key(|name| Reverse(priority(name)));
+        for name in names {
+            let Some(chunk) = chunks.get(name) else {
+                continue;
+            };
+            // 空きがなく、鳴っている音のほうが大事なときは鳴らさない
+            let Some(channel) = self.free_channel(priority(name)) else {
+                continue;
+            };
+            match Channel(channel as i32).play(chunk, 0) {
+                Ok(_) => self.priorities[channel] = priority(name),
+                Err(e) => println!("cannot play sound {}: {}", name, e),
+            }
+        }
+    }
+
+    // 空いているチャンネル。なければpriorityより低い音のうち一番低いものを止めて空ける
+    fn free_channel(&self, priority: u8) -> Option<usize> {
+        if let Some(channel) = (0..CHANNELS).find(|&i| !Channel(i as i32).is_playing()) {
+            return Some(channel);
+        }
+        let (channel, lowest) = self
+            .priorities
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, p)| **p)?;
+        if *lowest >= priority {
+            return None;
+        }
+        Channel(channel as i32).halt();
+        Some(channel)
+    }
+}
@@ -0,0 +1,327 @@
+// 標準入出力でつなぐボット
+//
+// ボットのプログラムを子プロセスとして起動し、1行1メッセージのJSONでやりとりする
+//   runner -> bot : {"type":"state","seq":0,"player":0,"error":null,"snapshot":{...}}
+//                   プレイヤーの番が来るたびに送る。snapshotは観戦用と同じ形式
+//                   seqは送るたびに1ずつ増える番号
+//                   霧のモード(--vision)では、そのプレイヤーに見えているものだけになる
+//                   errorは直前のコマンドが受け付けられなかった理由
+//   bot -> runner : "C" または "SEQ C" の行。Cは
+//                   "h" "j" "k" "l" "y" "u" "b" "n" "t" "." "1" "2" "3" "4" "c" "r" "f" のどれか1文字
+//                   1から4は道具を使う。c r f は砲台を乗っ取る・回す・撃つ(Command::from_charと同じ)
+//                   SEQは省略できる。付けるときは答えるstateのseq
+//                   seqが違う返事は、時間切れの後に遅れて届いた前のstateへの返事なので捨てる
+//   runner -> bot : {"type":"result","seed":3,"level":2,"score":40,...,"reason":"game_over"}
+//                   終了時に1回だけ送る
+//
+// 不正なコマンドや時間切れは、移動できなかったときと同じようにその場で1ターン待ったことにする
+// レベルをクリアすると自動で次のレベルに進む
+
+use crate::model::*;
+use crate::spectator::Snapshot;
+use serde::Serialize;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command as Process, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const BOT_TIMEOUT_MS: u64 = 1000;
+pub const BOT_TURN_LIMIT: i32 = 2000;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BotResult {
+    pub seed: u64,
+    pub level: i32,
+    pub score: i32,
+    pub turns: i32,
+    // 不正なコマンドの数
+    pub illegal: i32,
+    // 受け付けたが、ジャンクなどで移動できなかったコマンドの数
+    pub blocked: i32,
+    pub timeouts: i32,
+    // game_over, turn_limit, exited
+    pub reason: String,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message<'a> {
+    State {
+        seq: u64,
+        player: usize,
+        error: Option<String>,
+        snapshot: &'a Snapshot,
+    },
+    Result(&'a BotResult),
+}
+
+struct Bot {
+    child: Child,
+    stdin: ChildStdin,
+    receiver: Receiver<String>,
+}
+
+impl Bot {
+    fn spawn(command_line: &str) -> Result<Self, String> {
+        let words = split_command_line(command_line)?;
+        let (program, args) = words
+            .split_first()
+            .ok_or_else(|| "empty bot command".to_string())?;
+        let mut child = Process::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("cannot start {}: {}", program, e))?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        // 時間切れを判定できるように、読み込みは別スレッドで行う
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Bot {
+            child,
+            stdin,
+            receiver,
+        })
+    }
+
+    fn send(&mut self, message: &Message) -> bool {
+        match serde_json::to_string(message) {
+            Ok(line) => writeln!(self.stdin, "{}", line).is_ok(),
+            Err(e) => {
+                println!("cannot serialize bot message: {}", e);
+                false
+            }
+        }
+    }
+
+    fn kill(mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// ボットに1ゲーム遊ばせる。verboseなら毎ターン盤面を表示する
+pub fn play(command_line: &str, mut game: Game, timeout: Duration, verbose: bool) -> BotResult {
+    let mut result = BotResult {
+        seed: game.seed,
+        level: 0,
+        score: 0,
+        turns: 0,
+        illegal: 0,
+        blocked: 0,
+        timeouts: 0,
+        reason: "game_over".to_string(),
+    };
+    let mut bot = match Bot::spawn(command_line) {
+        Ok(bot) => bot,
+        Err(e) => {
+            println!("{}", e);
+            result.reason = "exited".to_string();
+            return result;
+        }
+    };
+    if verbose {
+        println!("{}", crate::headless::board_to_string(&game));
+    }
+
+    let mut error: Option<String> = None;
+    let mut seq = 0;
+    while !game.is_over {
+        if game.is_clear {
+            game.update(Command::NextLevel);
+        }
+        if game.turn >= BOT_TURN_LIMIT {
+            result.reason = "turn_limit".to_string();
+            break;
+        }
+        let snapshot = Snapshot::observe(&game, game.current_player);
+        seq += 1;
+        let state = Message::State {
+            seq,
+            player: game.current_player,
+            error: error.take(),
+            snapshot: &snapshot,
+        };
+        game.events.clear();
+        if !bot.send(&state) {
+            result.reason = "exited".to_string();
+            break;
+        }
+
+        // 前のstateへの返事を読み捨てている間も、時間切れはこのstateを送ったときから数える
+        let deadline = Instant::now() + timeout;
+        let command = loop {
+            match bot
+                .receiver
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Ok(line) => match parse_reply(&line) {
+                    Some((Some(reply_seq), _)) if reply_seq != seq => continue,
+                    Some((_, Some(command))) => break Some(command),
+                    _ => {
+                        result.illegal += 1;
+                        error = Some(format!("illegal command: {}", line));
+                        break Some(Command::Wait);
+                    }
+                },
+                Err(RecvTimeoutError::Timeout) => {
+                    result.timeouts += 1;
+                    error = Some("timeout".to_string());
+                    break Some(Command::Wait);
+                }
+                Err(RecvTimeoutError::Disconnected) => break None,
+            }
+        };
+        let Some(command) = command else {
+            result.reason = "exited".to_string();
+            break;
+        };
+        game.update(command);
+        if game
+            .events
+            .iter()
+            .any(|e| matches!(e, GameEvent::MoveBlocked { .. }))
+        {
+            result.blocked += 1;
+            error = Some("blocked".to_string());
+        }
+        game.requested_sounds.clear();
+        if verbose {
+            println!("{}", crate::headless::board_to_string(&game));
+        }
+    }
+
+    result.level = game.level;
+    result.score = game.score;
+    result.turns = game.turn;
+    bot.send(&Message::Result(&result));
+    bot.kill();
+    result
+}
+
+// "C" または "SEQ C" の行を番号とコマンドに分ける
+// 番号が読めなければNone、コマンドが不正ならSome((seq, None))
+fn parse_reply(line: &str) -> Option<(Option<u64>, Option<Command>)> {
+    match line.trim().split_once(' ') {
+        Some((seq, command)) => Some((Some(seq.parse().ok()?), parse_command(command.trim()))),
+        None => Some((None, parse_command(line.trim()))),
+    }
+}
+
+// ボットのコマンドラインを空白で分ける。"..." で囲んだ部分は空白を含めて1つの引数になる
+fn split_command_line(command_line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quoted = false;
+    for c in command_line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                word.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quoted {
+        return Err(format!(
+            "unterminated quote in bot command: {}",
+            command_line
+        ));
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+// 1文字のコマンドだけを受け付ける。次のレベルへは自動で進むので'>'は不正
+// リアルタイムモードの時計のコマンドも使えない
+fn parse_command(command: &str) -> Option<Command> {
+    let mut chars = command.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c != '>' => Command::from_char(c).filter(|c| !c.is_tick()),
+        _ => None,
+    }
+}
+
+pub fn result_to_string(result: &BotResult) -> String {
+    serde_json::to_string(result).unwrap_or_default()
+}
+
+// 同じseedの組で全部のボットを遊ばせて、合計スコアの順に並べる
+pub fn tournament(bots: &[String], seeds: &[u64], config: GameConfig, timeout: Duration) {
+    let mut standings: Vec<(&String, Vec<BotResult>)> = bots
+        .iter()
+        .map(|bot| {
+            let results = seeds
+                .iter()
+                .map(|seed| {
+                    let result = play(bot, Game::with_seed(*seed, config), timeout, false);
+                    println!("{}  {}", bot, result_to_string(&result));
+                    result
+                })
+                .collect();
+            (bot, results)
+        })
+        .collect();
+    standings.sort_by_key(|(_, results)| -results.iter().map(|r| r.score).sum::<i32>());
+
+    println!();
+    println!(
+        "{:>4}  {:>8}  {:>9}  {:>8}  {:>8}  {:>8}  bot",
+        "rank", "score", "avg level", "illegal", "blocked", "timeouts"
+    );
+    for (rank, (bot, results)) in standings.iter().enumerate() {
+        let sum = |f: fn(&BotResult) -> i32| results.iter().map(f).sum::<i32>();
+        println!(
+            "{:>4}  {:>8}  {:>9.2}  {:>8}  {:>8}  {:>8}  {}",
+            rank + 1,
+            sum(|r| r.score),
+            sum(|r| r.level) as f64 / results.len().max(1) as f64,
+            sum(|r| r.illegal),
+            sum(|r| r.blocked),
+            sum(|r| r.timeouts),
+            bot
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_with_and_without_seq() {
+        assert!(matches!(
+            parse_reply("7 h"),
+            Some((Some(7), Some(Command::Left)))
+        ));
+        assert!(matches!(
+            parse_reply("h\n"),
+            Some((None, Some(Command::Left)))
+        ));
+        assert!(matches!(parse_reply("7 >"), Some((Some(7), None))));
+        assert!(parse_reply("x h").is_none());
+        assert!(matches!(parse_reply("hj"), Some((None, None))));
+    }
+
+    #[test]
+    fn command_line_quotes() {
+        assert_eq!(
+            split_command_line(r#"python3 "my bots/a.py"  --depth 3"#).unwrap(),
+            vec!["python3", "my bots/a.py", "--depth", "3"]
+        );
+        assert_eq!(split_command_line(r#"a """#).unwrap(), vec!["a", ""]);
+        assert!(split_command_line(r#"python3 "a.py"#).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use crate::animation::TWEEN_FRAMES_DEFAULT;
+use crate::bot::BOT_TIMEOUT_MS;
+use crate::model::GameConfig;
+use crate::net::VersusMode;
+
+pub const SOLVE_DEPTH_DEFAULT: i32 = 20;
+// 観戦サーバーはこのマシンからだけつなげる
+pub const SERVE_ADDRESS_DEFAULT: &str = "127.0.0.1";
+
+#[derive(Debug, Default)]
+pub struct Options {
+    pub seed: Option<u64>,
+    pub config: GameConfig,
+    pub solve: bool,
+    pub solve_depth: i32,
+    pub solve_teleport: bool,
+    pub daily: bool,
+    pub share: bool,
+    pub headless: bool,
+    pub stats: bool,
+    pub host: Option<u16>,
+    pub join: Option<String>,
+    pub versus: VersusMode,
+    pub serve: Option<u16>,
+    // 観戦サーバーが待つアドレス
+    pub serve_address: String,
+    pub spectate: Option<String>,
+    pub bots: Vec<String>,
+    pub tournament: Option<u64>,
+    pub bot_timeout: u64,
+    pub script: Option<String>,
+    // 1回の動きを見せるフレーム数。0ならアニメーションしない
+    pub tween_frames: u32,
+    // テーマの名前かファイル
+    pub theme: Option<String>,
+    // 画像・音・フォントを最初に探すディレクトリ
+    pub assets: Option<String>,
+}
+
+pub fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        solve_depth: SOLVE_DEPTH_DEFAULT,
+        bot_timeout: BOT_TIMEOUT_MS,
+        serve_address: SERVE_ADDRESS_DEFAULT.to_string(),
+        tween_frames: TWEEN_FRAMES_DEFAULT,
+        ..Default::default()
+    };
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--seed" => options.seed = Some(parse_value(args, &mut i)?),
+            "--solvable" => options.config.solvable_depth = parse_value(args, &mut i)?,
+            "--solve" => options.solve = true,
+            "--depth" => options.solve_depth = parse_value(args, &mut i)?,
+            "--teleport" => options.solve_teleport = true,
+            "--daily" => options.daily = true,
+            "--share" => options.share = true,
+            "--headless" => options.headless = true,
+            "--stats" => options.stats = true,
+            "--two-players" => options.config.two_players = true,
+            "--torus" => options.config.torus = true,
+            "--topology" => options.config.topology = parse_value(args, &mut i)?,
+            "--vision" => options.config.vision = parse_value(args, &mut i)?,
+            "--realtime" => options.config.realtime = parse_value(args, &mut i)?,
+            "--host" => options.host = Some(parse_value(args, &mut i)?),
+            "--join" => options.join = Some(parse_value(args, &mut i)?),
+            "--versus" => options.versus = parse_value(args, &mut i)?,
+            "--serve" => options.serve = Some(parse_value(args, &mut i)?),
+            "--serve-address" => options.serve_address = parse_value(args, &mut i)?,
+            "--spectate" => options.spectate = Some(parse_value(args, &mut i)?),
+            "--bot-stdio" => options.bots.push(parse_value(args, &mut i)?),
+            "--tournament" => options.tournament = Some(parse_value(args, &mut i)?),
+            "--bot-timeout" => options.bot_timeout = parse_value(args, &mut i)?,
+            "--script" => options.script = Some(parse_value(args, &mut i)?),
+            "--tween" => options.tween_frames = parse_value(args, &mut i)?,
+            "--theme" => options.theme = Some(parse_value(args, &mut i)?),
+            "--assets" => options.assets = Some(parse_value(args, &mut i)?),
+            arg => return Err(format!("unknown argument: {}", arg)),
+        }
+        i += 1;
+    }
+    if options.daily && options.headless {
+        return Err("--daily cannot be used with --headless".to_string());
+    }
+    if (options.host.is_some() || options.join.is_some()) && (options.daily || options.headless) {
+        return Err("--host and --join cannot be used with --daily or --headless".to_string());
+    }
+    // ボットの対戦はいつもウィンドウなしで行うので、--headlessは付けても付けなくてもよい
+    if !options.bots.is_empty()
+        && (options.daily || options.host.is_some() || options.join.is_some())
+    {
+        return Err("--bot-stdio cannot be used with --daily, --host or --join".to_string());
+    }
+    if options.script.is_some()
+        && (options.daily || options.host.is_some() || options.join.is_some())
+    {
+        return Err("--script cannot be used with --daily, --host or --join".to_string());
+    }
+    if options.config.realtime > 0
+        && (options.headless
+            || options.config.two_players
+            || !options.bots.is_empty()
+            || options.host.is_some()
+            || options.join.is_some())
+    {
+        return Err(
+            "--realtime cannot be used with --headless, --two-players, --bot-stdio, --host or --join"
+                .to_string(),
+        );
+    }
+    if options.serve_address != SERVE_ADDRESS_DEFAULT && options.serve.is_none() {
+        return Err("--serve-address needs --serve".to_string());
+    }
+    if options.tournament.is_some() && options.bots.is_empty() {
+        return Err("--tournament needs at least one --bot-stdio".to_string());
+    }
+    Ok(options)
+}
+
+fn parse_value<T: std::str::FromStr>(args: &[String], i: &mut usize) -> Result<T, String> {
+    let name = &args[*i];
+    *i += 1;
+    let value = args
+        .get(*i)
+        .ok_or_else(|| format!("missing value for {}", name))?;
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", name, value))
+}
@@ -0,0 +1,190 @@
+use crate::model::*;
+use crate::storage;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Date {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    // 日付はUTCで決める。プレイヤーがどこにいても同じ日に同じ盤面になる
+    pub fn today() -> Self {
+        let secs = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .expect("SystemTime before UNIX EPOCH!")
+            .as_secs();
+        Date::from_days((secs / 86400) as i64)
+    }
+
+    // 1970-01-01からの日数を年月日に変換する
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    pub fn from_days(days: i64) -> Self {
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = (yoe + era * 400 + if month <= 2 { 1 } else { 0 }) as i32;
+        Date { year, month, day }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.year as u64 * 10000 + self.month as u64 * 100 + self.day as u64
+    }
+}
+
+impl std::fmt::Display for Date {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DailyResult {
+    pub date: String,
+    pub seed: u64,
+    pub score: i32,
+    pub level: i32,
+    pub turns: i32,
+    pub finished: bool,
+    pub replay: String,
+    // リプレイを再生するときの設定。古いファイルにはないのでデフォルトにする
+    pub config: GameConfig,
+}
+
+impl DailyResult {
+    pub fn from_game(date: &Date, game: &Game) -> Self {
+        DailyResult {
+            date: date.to_string(),
+            seed: game.seed,
+            score: game.score,
+            level: game.level,
+            turns: game.turn,
+            finished: game.is_over,
+            replay: game.history.iter().filter_map(|c| c.to_char()).collect(),
+            config: game.config,
+        }
+    }
+
+    fn path(date: &Date) -> PathBuf {
+        storage::data_dir()
+            .join("daily")
+            .join(format!("{}.txt", date))
+    }
+
+    pub fn load(date: &Date) -> Option<Self> {
+        let map = storage::read_kv(&DailyResult::path(date))?;
+        Some(DailyResult {
+            date: date.to_string(),
+            seed: storage::get_parsed(&map, "seed")?,
+            score: storage::get_parsed(&map, "score").unwrap_or(0),
+            level: storage::get_parsed(&map, "level").unwrap_or(1),
+            turns: storage::get_parsed(&map, "turns").unwrap_or(0),
+            finished: storage::get_parsed(&map, "finished").unwrap_or(true),
+            replay: map.get("replay").cloned().unwrap_or_default(),
+            config: map
+                .get("config")
+                .and_then(|v| serde_json::from_str(v).ok())
+                .unwrap_or_default(),
+        })
+    }
+
+    pub fn save(&self, date: &Date) -> Result<(), String> {
+        let mut map = BTreeMap::new();
+        map.insert("date".to_string(), self.date.clone());
+        map.insert("seed".to_string(), self.seed.to_string());
+        map.insert("score".to_string(), self.score.to_string());
+        map.insert("level".to_string(), self.level.to_string());
+        map.insert("turns".to_string(), self.turns.to_string());
+        map.insert("finished".to_string(), self.finished.to_string());
+        map.insert("replay".to_string(), self.replay.clone());
+        map.insert(
+            "config".to_string(),
+            serde_json::to_string(&self.config).map_err(|e| e.to_string())?,
+        );
+        storage::write_kv(&DailyResult::path(date), &map)
+    }
+
+    pub fn commands(&self) -> Vec<Command> {
+        self.replay.chars().filter_map(Command::from_char).collect()
+    }
+
+    // リプレイを再生して、記録されたスコアと一致するか確かめる
+    pub fn verify(&self) -> bool {
+        let game = Game::replay(self.seed, self.config, &self.commands());
+        game.score == self.score && game.level == self.level
+    }
+
+    // SNSなどに貼るための短い要約
+    pub fn summary(&self) -> String {
+        let bar: String = (1..=self.level)
+            .map(|l| if l < self.level { '#' } else { 'x' })
+            .collect();
+        format!(
+            "rust-robots daily {}\nlevel {}  score {}  turns {}\n{}",
+            self.date, self.level, self.score, self.turns, bar
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> Date {
+        Date { year, month, day }
+    }
+
+    #[test]
+    fn from_days_epoch() {
+        assert_eq!(Date::from_days(0), date(1970, 1, 1));
+        assert_eq!(Date::from_days(-1), date(1969, 12, 31));
+    }
+
+    #[test]
+    fn from_days_leap_years() {
+        // 2000年は400で割り切れるのでうるう年
+        assert_eq!(Date::from_days(11015), date(2000, 2, 28));
+        assert_eq!(Date::from_days(11016), date(2000, 2, 29));
+        assert_eq!(Date::from_days(11017), date(2000, 3, 1));
+        // 1900年は100で割り切れて400で割り切れないのでうるう年でない
+        assert_eq!(Date::from_days(-25509), date(1900, 2, 28));
+        assert_eq!(Date::from_days(-25508), date(1900, 3, 1));
+        assert_eq!(Date::from_days(19416), date(2023, 2, 28));
+        assert_eq!(Date::from_days(19417), date(2023, 3, 1));
+        assert_eq!(Date::from_days(19782), date(2024, 2, 29));
+    }
+
+    #[test]
+    fn from_days_year_end() {
+        assert_eq!(Date::from_days(20088), date(2024, 12, 31));
+        assert_eq!(Date::from_days(20089), date(2025, 1, 1));
+        assert_eq!(Date::from_days(20089).seed(), 20250101);
+        assert_eq!(Date::from_days(20089).to_string(), "2025-01-01");
+    }
+
+    #[test]
+    fn verify_replays_with_the_saved_config() {
+        // この手順は1人のときとスコアが変わる
+        let config = GameConfig {
+            two_players: true,
+            ..Default::default()
+        };
+        let commands: Vec<Command> = "hhhhjjjjllll"
+            .chars()
+            .filter_map(Command::from_char)
+            .collect();
+        let game = Game::replay(20250101, config, &commands);
+        let result = DailyResult::from_game(&date(2025, 1, 1), &game);
+        assert!(result.config.two_players);
+        assert!(result.verify());
+    }
+}
@@ -0,0 +1,47 @@
+// 1フレームの処理にかかった時間の記録
+//
+// 大きな盤面で重くなっていないかを確かめるために、F8で画面の左上に出す
+// 待ち時間は含めず、入力からpresentまでを測る
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+// 平均と最大をとるフレーム数
+pub const FRAME_SAMPLES: usize = 120;
+
+#[derive(Default)]
+pub struct FrameStats {
+    pub visible: bool,
+    samples: VecDeque<Duration>,
+}
+
+impl FrameStats {
+    pub fn push(&mut self, elapsed: Duration) {
+        if self.samples.len() == FRAME_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(elapsed);
+    }
+
+    pub fn average(&self) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::ZERO;
+        }
+        self.samples.iter().sum::<Duration>() / self.samples.len() as u32
+    }
+
+    pub fn max(&self) -> Duration {
+        self.samples.iter().max().copied().unwrap_or_default()
+    }
+
+    // 画面に出す1行
+    pub fn summary(&self) -> String {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        format!(
+            "FRAME {:.1}MS  AVG {:.1}MS  MAX {:.1}MS",
+            ms(self.samples.back().copied().unwrap_or_default()),
+            ms(self.average()),
+            ms(self.max())
+        )
+    }
+}
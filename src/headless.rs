@@ -0,0 +1,205 @@
+use crate::cli::Options;
+use crate::model::*;
+use crate::profile::{self, Profile};
+use crate::spectator::SpectatorServer;
+use crate::topology::TopologyKind;
+use std::io::{self, BufRead};
+
+// SDLを使わずに端末で遊ぶ。標準入力から1行ずつコマンド文字を読む
+pub fn run(options: &Options) {
+    let mut game = crate::new_game(options);
+    let mut profile = Profile::load();
+    let server = match options
+        .serve
+        .map(|port| SpectatorServer::start(&options.serve_address, port))
+    {
+        Some(Ok(server)) => Some(server),
+        Some(Err(e)) => {
+            println!("cannot start spectator server: {}", e);
+            None
+        }
+        None => None,
+    };
+    if let Some(server) = &server {
+        server.send(&game);
+    }
+    update_profile(&mut profile, &mut game);
+    println!("{}", board_to_string(&game));
+
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        for c in line.chars() {
+            match Command::from_char(c) {
+                Some(command) => game.update(command),
+                None => println!("unknown command: {}", c),
+            }
+            // クリアやゲームオーバーの後の入力は捨てる
+            if game.is_clear || game.is_over {
+                break;
+            }
+        }
+        println!("{}", board_to_string(&game));
+        if let Some(server) = &server {
+            server.send(&game);
+        }
+        update_profile(&mut profile, &mut game);
+        if game.is_clear {
+            println!("YOU WIN!");
+            print_summary(&game);
+            println!("enter > to go to the next level");
+        }
+        if game.is_over {
+            println!("GAME OVER");
+            print_summary(&game);
+            break;
+        }
+    }
+    // 途中でやめたときも、それまでの記録を残す
+    if let Err(e) = profile.save() {
+        println!("cannot save profile: {}", e);
+    }
+}
+
+fn update_profile(profile: &mut Profile, game: &mut Game) {
+    let mut save = false;
+    for event in game.events.drain(..) {
+        let unlocked = profile.handle_event(&event, None);
+        save |= !unlocked.is_empty() || profile::is_checkpoint(&event);
+        for achievement in unlocked {
+            println!(
+                "ACHIEVEMENT UNLOCKED: {} - {}",
+                achievement.title(),
+                achievement.description()
+            );
+        }
+    }
+    if save {
+        if let Err(e) = profile.save() {
+            println!("cannot save profile: {}", e);
+        }
+    }
+}
+
+pub fn print_summary(game: &Game) {
+    for line in game.stats.summary_lines() {
+        println!("{}", line);
+    }
+    println!("SCORE {}  TOTAL TURNS {}", game.score, game.turn);
+    if game.players.len() > 1 {
+        println!("{}", game.player_scores());
+    }
+}
+
+const PLAYER_CHARS: [char; 2] = ['@', '&'];
+
+// @ &: プレイヤー  +: ロボット  #: ジャンク  C: レーザー砲  *: レーザー
+// e s j f c: 道具(EMP, シールド, ジャンク, フリーズ, 砲台)
+// 霧のモードでは見えていないマスが空白、ロボットを最後に見た位置が?
+pub fn board_to_string(game: &Game) -> String {
+    let mut cells = vec![vec!['.'; FIELD_W as usize]; FIELD_H as usize];
+    for (y, row) in cells.iter_mut().enumerate() {
+        for (x, cell) in row.iter_mut().enumerate() {
+            if !game.is_visible(
+                None,
+                Vec2 {
+                    x: x as i32,
+                    y: y as i32,
+                },
+            ) {
+                *cell = ' ';
+            }
+        }
+    }
+    if game.laser_cannon.is_shooting {
+        for cell in &game.laser_cannon.beam {
+            if game.is_visible(None, *cell) {
+                cells[cell.y as usize][cell.x as usize] = '*';
+            }
+        }
+    }
+    for junk in &game.junks {
+        if game.is_visible(None, junk.pos) {
+            cells[junk.pos.y as usize][junk.pos.x as usize] = '#';
+        }
+    }
+    for item in &game.items {
+        if game.is_visible(None, item.pos) {
+            cells[item.pos.y as usize][item.pos.x as usize] = item.kind.letter();
+        }
+    }
+    for pos in game.remembered_robots(None) {
+        cells[pos.y as usize][pos.x as usize] = '?';
+    }
+    for robot in &game.robots {
+        if game.is_visible(None, robot.pos) {
+            cells[robot.pos.y as usize][robot.pos.x as usize] = '+';
+        }
+    }
+    if game.is_cannon_seen(None) {
+        let cannon = game.laser_cannon.pos;
+        cells[cannon.y as usize][cannon.x as usize] = 'C';
+    }
+    for (i, player) in game.players.iter().enumerate() {
+        if player.alive {
+            cells[player.pos.y as usize][player.pos.x as usize] = PLAYER_CHARS[i];
+        }
+    }
+
+    let mut result = format!(
+        "LEVEL {}   ROBOTS {}   TURN {}",
+        game.level,
+        game.robots_left(),
+        game.turn
+    );
+    if game.players.len() > 1 {
+        result.push_str(&format!("   {}", game.player_scores()));
+        result.push_str(&format!("   NEXT P{}", game.current_player + 1));
+    }
+    if game.freeze > 0 {
+        result.push_str(&format!("   FREEZE {}", game.freeze));
+    }
+    if let Some(player) = game.laser_cannon.controller {
+        result.push_str(&format!(
+            "   CANNON P{} {:?} {} TURNS",
+            player + 1,
+            game.laser_cannon.direction,
+            game.laser_cannon.control_turns
+        ));
+        if game.laser_cannon.cooldown > 0 {
+            result.push_str(&format!(" (COOLDOWN {})", game.laser_cannon.cooldown));
+        }
+    }
+    result.push('\n');
+    for (i, player) in game.players.iter().enumerate() {
+        if !player.items.is_empty() {
+            let names: Vec<&str> = player.items.iter().map(|k| k.name()).collect();
+            result.push_str(&format!("P{} ITEMS {}\n", i + 1, names.join(" ")));
+        }
+    }
+    if let Some(error) = &game.script_error {
+        result.push_str(&format!("SCRIPT ERROR: {}\n", error));
+    }
+    if let Some(warning) = &game.level_warning {
+        result.push_str(&format!("WARNING: {}\n", warning));
+    }
+    let hex = game.config.topology == TopologyKind::Hex;
+    for (y, row) in cells.into_iter().enumerate() {
+        if hex {
+            // 六角形の盤面は1マスを2文字にして、奇数行を半マスずらす
+            if y % 2 == 1 {
+                result.push(' ');
+            }
+            for c in row {
+                result.push(c);
+                result.push(' ');
+            }
+        } else {
+            result.extend(row);
+        }
+        result.push('\n');
+    }
+    result
+}
@@ -1,74 +1,412 @@
-use sdl2::event::Event;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::image::ImageRWops;
 use sdl2::keyboard::Keycode;
 use sdl2::mixer;
-use sdl2::pixels::Color;
+use sdl2::mixer::LoaderRWops;
+use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::{Point, Rect};
 use sdl2::render::{BlendMode, Canvas, Texture, TextureCreator};
+use sdl2::rwops::RWops;
+use sdl2::surface::Surface;
 use sdl2::ttf::Sdl2TtfContext;
-use sdl2::video::{Window, WindowContext};
-use std::collections::HashMap;
-use std::fs;
-use std::time::{Duration, SystemTime};
+use sdl2::video::{FullscreenType, Window, WindowContext};
+use std::collections::{HashMap, VecDeque};
+use std::io::Read;
+use std::time::{Duration, Instant, SystemTime};
+mod accessibility;
+mod animation;
+mod assets;
+mod atlas;
+mod audio;
+mod bot;
+mod cli;
+mod daily;
+mod frame_stats;
+mod headless;
 mod model;
+mod net;
+mod particles;
+mod profile;
+mod scheduler;
+mod script;
+mod settings;
+mod solver;
+mod spectator;
+mod sprite_sheet;
+mod storage;
+mod theme;
+mod topology;
+use crate::animation::Animator;
+use crate::assets::{AssetKind, AssetSource, AssetWatcher, Assets, DEFAULT_FONT};
+use crate::atlas::{Atlas, Batch, SpriteKey, TextCache};
+use crate::audio::{step_volume, Audio};
+use crate::cli::Options;
+use crate::daily::{DailyResult, Date};
+use crate::frame_stats::FrameStats;
 use crate::model::*;
+use crate::net::{Session, VersusMode};
+use crate::particles::{ParticleKind, Particles};
+use crate::profile::{Achievement, Profile};
+use crate::scheduler::Scheduler;
+use crate::script::Script;
+use crate::settings::{ScaleMode, Settings};
+use crate::solver::{SolveResult, SolverConfig};
+use crate::spectator::{SpectatorClient, SpectatorServer};
+use crate::sprite_sheet::{AnimKind, SpriteSheet};
+use crate::theme::{Look, Rgb, Shape, Theme};
+use crate::topology::TopologyKind;
 
 pub const WINDOW_TITLE: &str = "rust-robots";
 pub const SCREEN_WIDTH: i32 = FIELD_W * CELL_W;
 pub const SCREEN_HEIGHT: i32 = FIELD_H * CELL_H + INFO_HEIGHT;
 pub const INFO_HEIGHT: i32 = 28;
-pub const SOUND_WAIT: i32 = 4;
+pub const TOAST_FRAMES: i32 = FPS * 3;
+pub const TORUS_MARGIN: i32 = 2;
+pub const STATUS_MAX_CHARS: usize = 48;
+// 書き換えられたアセットを探す間隔
+pub const ASSET_WATCH_INTERVAL: i32 = FPS;
+// HUDに並べる道具の数の上限
+pub const INVENTORY_MAX: usize = 6;
 
-struct Image<'a> {
-    texture: Texture<'a>,
-    #[allow(dead_code)]
-    w: u32,
-    h: u32,
+// 実績解除の通知
+struct Toast {
+    title: String,
+    description: String,
+    frames: i32,
 }
 
-impl<'a> Image<'a> {
-    fn new(texture: Texture<'a>) -> Self {
-        let q = texture.query();
-        Image {
-            texture,
-            w: q.width,
-            h: q.height,
+impl Toast {
+    fn new(achievement: Achievement) -> Self {
+        Toast {
+            title: format!("UNLOCKED: {}", achievement.title()),
+            description: achievement.description().to_string(),
+            frames: TOAST_FRAMES,
         }
     }
+
+    fn message(title: &str, description: &str) -> Self {
+        Toast {
+            title: title.to_string(),
+            description: description.to_string(),
+            frames: TOAST_FRAMES,
+        }
+    }
+}
+
+// 盤面を描く位置と画面の大きさ
+// トーラスのときは盤面の周りに余白をとって、反対側の端の近くにあるものの写し(ゴースト)を描く
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct Layout {
+    // 余白のマス数
+    margin: i32,
+    // 六角形の盤面は奇数行を半マス右にずらして描く
+    hex: bool,
+    width: i32,
+    height: i32,
+}
+
+impl Layout {
+    fn new(config: &GameConfig) -> Self {
+        let margin = if config.torus { TORUS_MARGIN } else { 0 };
+        let hex = config.topology == TopologyKind::Hex;
+        Layout {
+            margin,
+            hex,
+            width: SCREEN_WIDTH + margin * 2 * CELL_W + if hex { CELL_W / 2 } else { 0 },
+            height: SCREEN_HEIGHT + margin * 2 * CELL_H,
+        }
+    }
+
+    fn cell_rect(&self, pos: Vec2) -> Rect {
+        let shift = if self.hex && pos.y.rem_euclid(2) == 1 {
+            CELL_W / 2
+        } else {
+            0
+        };
+        Rect::new(
+            (pos.x + self.margin) * CELL_W + shift,
+            (pos.y + self.margin) * CELL_H + INFO_HEIGHT,
+            CELL_W as u32,
+            CELL_H as u32,
+        )
+    }
+
+    // マスを塗る横線。六角形の盤面では六角形にする
+    fn cell_rows(&self, pos: Vec2) -> Vec<Rect> {
+        self.shape_rows(self.cell_rect(pos))
+    }
+
+    // マスの形をrectに塗る横線。動いている途中のものはマス目からずれたところに描く
+    fn shape_rows(&self, rect: Rect) -> Vec<Rect> {
+        if !self.hex {
+            return vec![rect];
+        }
+        // 上下の頂点がとがった六角形を1行ずつ塗る
+        let (w, h) = (rect.width() as i32, rect.height() as i32);
+        let slope = h / 4;
+        (0..h)
+            .map(|y| {
+                let edge = (slope - y).max(y - (h - 1 - slope)).max(0);
+                let inset = w / 2 * edge / slope;
+                Rect::new(
+                    rect.x() + inset,
+                    rect.y() + y,
+                    (w - inset * 2).max(1) as u32,
+                    1,
+                )
+            })
+            .collect()
+    }
+
+    // ウィンドウのピクセル座標を論理サイズの座標に直す。SDLと同じく縦横比を保って真ん中に描き、周りは黒帯
+    // integerなら倍率は整数(1倍より小さくはしない)。黒帯の上ならNone
+    fn window_to_logical(
+        &self,
+        window: (u32, u32),
+        integer: bool,
+        x: i32,
+        y: i32,
+    ) -> Option<(i32, i32)> {
+        let (w, h) = (window.0 as f32, window.1 as f32);
+        let mut scale = (w / self.width as f32).min(h / self.height as f32);
+        if integer {
+            scale = scale.floor().max(1.0);
+        }
+        let left = (w - self.width as f32 * scale) / 2.0;
+        let top = (h - self.height as f32 * scale) / 2.0;
+        let lx = ((x as f32 - left) / scale).floor() as i32;
+        let ly = ((y as f32 - top) / scale).floor() as i32;
+        ((0..self.width).contains(&lx) && (0..self.height).contains(&ly)).then_some((lx, ly))
+    }
+
+    // 論理座標にあるマス。余白の写しのマスは盤面の外の位置のまま返す
+    fn cell_at(&self, x: i32, y: i32) -> Option<Vec2> {
+        let row = (y - INFO_HEIGHT).div_euclid(CELL_H) - self.margin;
+        let shift = if self.hex && row.rem_euclid(2) == 1 {
+            CELL_W / 2
+        } else {
+            0
+        };
+        let column = (x - shift).div_euclid(CELL_W) - self.margin;
+        ((-self.margin..FIELD_W + self.margin).contains(&column)
+            && (-self.margin..FIELD_H + self.margin).contains(&row))
+        .then_some(Vec2 { x: column, y: row })
+    }
+
+    // posと、余白に描くその写しの位置
+    fn copies(&self, pos: Vec2) -> Vec<Vec2> {
+        let mut result = Vec::new();
+        for dy in [0, -FIELD_H, FIELD_H] {
+            for dx in [0, -FIELD_W, FIELD_W] {
+                let p = Vec2 {
+                    x: pos.x + dx,
+                    y: pos.y + dy,
+                };
+                if (-self.margin..FIELD_W + self.margin).contains(&p.x)
+                    && (-self.margin..FIELD_H + self.margin).contains(&p.y)
+                {
+                    result.push(p);
+                }
+            }
+        }
+        result
+    }
 }
 
 struct Resources<'a> {
-    images: HashMap<String, Image<'a>>,
+    images: HashMap<String, Texture<'a>>,
+    // 画像の名前ごとのスプライトシート
+    sheets: HashMap<String, SpriteSheet>,
     chunks: HashMap<String, sdl2::mixer::Chunk>,
-    fonts: HashMap<String, sdl2::ttf::Font<'a, 'a>>,
+    // テーマで文字で描くものの画像
+    glyphs: HashMap<char, Texture<'a>>,
+    // imagesとglyphsをまとめたもの。描くときはこちらを使う
+    atlas: Option<Atlas<'a>>,
+    // テーマのフォントで描いた文字列
+    texts: TextCache<'a>,
+    // 画像・音・フォントを探す場所
+    assets: Assets,
+    // 音が出せるか
+    audio: bool,
+    // 読み込みでおかしかったこと。画面とターミナルに出す
+    warnings: Vec<String>,
 }
 
 pub fn main() -> Result<(), String> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut options = cli::parse_args(&args)?;
+
+    if options.solve {
+        run_solver(&options);
+        return Ok(());
+    }
+
+    if options.stats {
+        for line in Profile::load().summary_lines() {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+
+    let today = Date::today();
+    if options.share {
+        match DailyResult::load(&today) {
+            Some(result) => println!("{}", result.summary()),
+            None => println!("no daily result for {}", today),
+        }
+        return Ok(());
+    }
+    if options.daily {
+        // デイリーは1日1回だけ
+        if let Some(result) = DailyResult::load(&today) {
+            println!("you have already played the daily challenge for {}", today);
+            if !result.verify() {
+                println!("warning: the saved replay does not match the saved result");
+            }
+            println!("{}", result.summary());
+            return Ok(());
+        }
+        options.seed = Some(today.seed());
+        options.config = GameConfig::default();
+    }
+
+    if !options.bots.is_empty() {
+        run_bots(&options);
+        return Ok(());
+    }
+
+    if options.headless {
+        headless::run(&options);
+        return Ok(());
+    }
+
+    let mut session = if let Some(port) = options.host {
+        let seed = options.seed.unwrap_or_else(model::timestamp_seed);
+        Some(Session::host(port, seed, options.versus, options.config)?)
+    } else if let Some(addr) = &options.join {
+        Some(Session::join(addr)?)
+    } else {
+        None
+    };
+
+    let server = match options.serve {
+        Some(port) => Some(SpectatorServer::start(&options.serve_address, port)?),
+        None => None,
+    };
+    let mut spectator = match &options.spectate {
+        Some(addr) => Some(SpectatorClient::connect(addr)?),
+        None => None,
+    };
+
+    let (mut themes, errors) = theme::load_all();
+    for e in errors {
+        println!("{}", e);
+    }
+    let mut settings = Settings::load();
+    // --themeはテーマの名前かファイル。見つからなければ始めない
+    let mut theme_index = match &options.theme {
+        Some(name) if std::path::Path::new(name).is_file() => {
+            themes.push(theme::load_file(std::path::Path::new(name))?);
+            themes.len() - 1
+        }
+        Some(name) => themes
+            .iter()
+            .position(|t| t.name == *name)
+            .ok_or_else(|| format!("unknown theme: {}", name))?,
+        None => themes
+            .iter()
+            .position(|t| t.name == settings.theme)
+            .unwrap_or(0),
+    };
+
+    let mut layout = Layout::new(&match &session {
+        Some(session) => session.config,
+        None => options.config,
+    });
+
     let sdl_context = sdl2::init()?;
 
     let video_subsystem = sdl_context.video()?;
-    let window = video_subsystem
-        .window(WINDOW_TITLE, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)
+    // 拡大してもドットをぼかさない
+    sdl2::hint::set("SDL_RENDER_SCALE_QUALITY", "0");
+    let (window_width, window_height) = settings
+        .window_size
+        .unwrap_or_else(|| initial_window_size(&video_subsystem, &layout));
+    let mut window = video_subsystem
+        .window(WINDOW_TITLE, window_width, window_height)
         .position_centered()
+        .resizable()
         .opengl()
         .build()
         .map_err(|e| e.to_string())?;
+    // 論理サイズより小さいと整数倍で描けない
+    window
+        .set_minimum_size(layout.width as u32, layout.height as u32)
+        .map_err(|e| e.to_string())?;
+    if settings.fullscreen {
+        window.set_fullscreen(FullscreenType::Desktop)?;
+    }
 
-    sdl_context.mouse().show_cursor(false);
-
-    init_mixer();
-    let mut sound_wait: i32 = 0;
+    let mut audio = Audio::new(init_mixer(), settings.volume);
 
     let ttf_context = sdl2::ttf::init().map_err(|e| e.to_string())?;
+    // PNGが読めなくてもBMPの画像は使える
+    let _image_context = sdl2::image::init(sdl2::image::InitFlag::PNG)
+        .map_err(|e| {
+            println!(
+                "warning: cannot init SDL_image, PNG images will not load: {}",
+                e
+            )
+        })
+        .ok();
 
     let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
     canvas.set_blend_mode(BlendMode::Blend);
+    // 描くのはいつも盤面の大きさで、ウィンドウへの拡大はSDLに任せる。情報欄や文字も一緒に拡大される
+    canvas
+        .set_logical_size(layout.width as u32, layout.height as u32)
+        .map_err(|e| e.to_string())?;
+    canvas.set_integer_scale(settings.scale == ScaleMode::Integer)?;
 
     let texture_creator = canvas.texture_creator();
-    let mut resources = load_resources(&texture_creator, &mut canvas, &ttf_context);
+    let assets = Assets::new(options.assets.as_deref().map(std::path::Path::new));
+    let mut resources = load_resources(&texture_creator, assets, audio.enabled());
+    // 画面に使うのは、選んだテーマに色の見分けにくさへの対応をかぶせたもの
+    let mut theme = settings.accessibility.apply(&themes[theme_index]);
+    if let Err(e) = apply_theme(
+        &mut canvas,
+        &theme,
+        &mut resources,
+        &texture_creator,
+        &ttf_context,
+    ) {
+        if options.theme.is_some() {
+            return Err(e);
+        }
+        // 保存してあったテーマが使えなくなっていたらclassicに戻す
+        println!("{}", e);
+        theme_index = 0;
+        theme = settings.accessibility.apply(&themes[0]);
+        apply_theme(
+            &mut canvas,
+            &theme,
+            &mut resources,
+            &texture_creator,
+            &ttf_context,
+        )?;
+    }
 
     let mut event_pump = sdl_context.event_pump()?;
 
-    let mut game = Game::new();
+    let mut profile = Profile::load();
+    let mut toasts: Vec<Toast> = Vec::new();
+    let mut game = match &session {
+        Some(session) => session.new_game(),
+        None => new_game(&options),
+    };
+    if let Some(server) = &server {
+        server.send(&game);
+    }
 
     println!("h      : Move left");
     println!("j      : Move down");
@@ -80,322 +418,1780 @@ pub fn main() -> Result<(), String> {
     println!("n      : Move down and right");
     println!("t      : Teleport to a random location");
     println!(".      : Do nothing for one turn");
+    println!("1      : Use EMP (destroys adjacent robots)");
+    println!("2      : Use junk dropper (drops junk behind you)");
+    println!(
+        "3      : Use freeze (robots stop for {} turns)",
+        model::FREEZE_TURNS
+    );
+    println!("4      : Use cannon control (fires the laser cannon)");
+    println!("i      : Take over the laser cannon next to you");
+    println!("o      : Rotate the cannon you control");
+    println!("p      : Fire the cannon you control");
+    println!("         (in --headless mode, bots and replays the cannon commands are c r f)");
     println!("Enter  : Restart");
+    println!("F2     : Toggle particles and screen shake");
+    println!("F3     : Change the theme");
+    println!("F4     : Change the color palette (deuteranopia, protanopia, tritanopia)");
+    println!("F5     : Toggle shapes for robots, junk and the laser");
+    println!("F6     : Toggle high-contrast outlines");
+    println!("F7     : Switch between integer scaling and fit to window");
+    println!("F8     : Toggle the frame time overlay");
+    println!("F11    : Toggle fullscreen");
+    if options.config.topology == TopologyKind::Hex {
+        println!();
+        println!("Hex board: y u h l b n to move (j and k are not used)");
+    }
+    if options.config.two_players {
+        println!();
+        if options.config.topology == TopologyKind::Hex {
+            println!("Player 2: w e a d z x to move, s to wait, f to teleport");
+        } else {
+            println!("Player 2: q w e a d z x c to move, s to wait, f to teleport");
+        }
+        println!("Player 2: 7 8 9 0 to use items, r g v to take, rotate and fire the cannon");
+    }
+
+    let mut scheduler = Scheduler::default();
+    let mut animator = Animator::new(options.tween_frames);
+    let mut particles = Particles::new(settings.particles);
+    let mut frame_stats = FrameStats::default();
+    let mut watcher = AssetWatcher::default();
+    let mut watch_wait: i32 = 0;
+    // アニメーションの間に押されたキー。(プレイヤー, コマンド)
+    let mut input: VecDeque<(usize, Command)> = VecDeque::new();
 
     'running: loop {
         let started = SystemTime::now();
 
-        let mut command = Command::None;
+        // このフレームで受け付けたコマンド。来た順にupdateに渡す
+        let mut commands: Vec<Command> = Vec::new();
 
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit { .. } => break 'running,
+                // 描画先のテクスチャは消えることがあるので、アトラスを作り直す
+                Event::RenderTargetsReset { .. } => {
+                    build_atlas(&mut canvas, &texture_creator, &mut resources)?;
+                }
+                // 全画面でないときの大きさを終わるときに保存する
+                Event::Window {
+                    win_event: WindowEvent::Resized(w, h),
+                    ..
+                } if !settings.fullscreen => {
+                    settings.window_size = Some((w as u32, h as u32));
+                }
                 Event::KeyDown {
                     keycode: Some(code),
                     ..
                 } => {
                     match code {
                         Keycode::Escape => break 'running,
-                        Keycode::Return => {
-                            if !game.is_clear {
-                                game = Game::new();
+                        Keycode::F2 => {
+                            settings.particles = !settings.particles;
+                            particles.set_enabled(settings.particles);
+                            if let Err(e) = settings.save() {
+                                println!("cannot save settings: {}", e);
+                            }
+                            toasts.push(Toast::message(
+                                if settings.particles {
+                                    "PARTICLES ON"
+                                } else {
+                                    "PARTICLES OFF"
+                                },
+                                "F2 to toggle",
+                            ));
+                        }
+                        Keycode::F3 => {
+                            let next = (theme_index + 1) % themes.len();
+                            let next_theme = settings.accessibility.apply(&themes[next]);
+                            match apply_theme(
+                                &mut canvas,
+                                &next_theme,
+                                &mut resources,
+                                &texture_creator,
+                                &ttf_context,
+                            ) {
+                                Ok(()) => {
+                                    theme_index = next;
+                                    theme = next_theme;
+                                    settings.theme = themes[next].name.clone();
+                                    if let Err(e) = settings.save() {
+                                        println!("cannot save settings: {}", e);
+                                    }
+                                    toasts.push(Toast::message(
+                                        &format!("THEME: {}", settings.theme.to_uppercase()),
+                                        "F3 to change",
+                                    ));
+                                }
+                                // 使えないテーマは一覧から外す
+                                Err(e) => {
+                                    println!("{}", e);
+                                    themes.remove(next);
+                                    if next < theme_index {
+                                        theme_index -= 1;
+                                    }
+                                    toasts.push(Toast::message(
+                                        "THEME ERROR",
+                                        "see the terminal for details",
+                                    ));
+                                }
+                            }
+                        }
+                        // 色の見分けにくさへの対応。テーマの画像や文字は変わらないので作り直すだけでよい
+                        Keycode::F4 | Keycode::F5 | Keycode::F6 => {
+                            let a = &mut settings.accessibility;
+                            let (title, desc) = match code {
+                                Keycode::F4 => {
+                                    a.palette = a.palette.next();
+                                    (
+                                        format!("PALETTE: {}", a.palette.name().to_uppercase()),
+                                        "F4 to change",
+                                    )
+                                }
+                                Keycode::F5 => {
+                                    a.shapes = !a.shapes;
+                                    (
+                                        format!("SHAPES {}", if a.shapes { "ON" } else { "OFF" }),
+                                        "F5 to toggle",
+                                    )
+                                }
+                                _ => {
+                                    a.outline = !a.outline;
+                                    (
+                                        format!(
+                                            "OUTLINES {}",
+                                            if a.outline { "ON" } else { "OFF" }
+                                        ),
+                                        "F6 to toggle",
+                                    )
+                                }
+                            };
+                            theme = settings.accessibility.apply(&themes[theme_index]);
+                            if let Err(e) = settings.save() {
+                                println!("cannot save settings: {}", e);
+                            }
+                            toasts.push(Toast::message(&title, desc));
+                        }
+                        Keycode::F7 => {
+                            settings.scale = match settings.scale {
+                                ScaleMode::Integer => ScaleMode::Fit,
+                                ScaleMode::Fit => ScaleMode::Integer,
+                            };
+                            canvas.set_integer_scale(settings.scale == ScaleMode::Integer)?;
+                            if let Err(e) = settings.save() {
+                                println!("cannot save settings: {}", e);
+                            }
+                            toasts.push(Toast::message(
+                                &format!("SCALE: {}", settings.scale.name().to_uppercase()),
+                                "F7 to change",
+                            ));
+                        }
+                        Keycode::F8 => frame_stats.visible = !frame_stats.visible,
+                        // 音量。- =で全体、[ ]で効果音を変える
+                        Keycode::Minus
+                        | Keycode::Equals
+                        | Keycode::LeftBracket
+                        | Keycode::RightBracket
+                        | Keycode::M => {
+                            let mut volume = audio.volume();
+                            match code {
+                                Keycode::Minus => volume.master = step_volume(volume.master, false),
+                                Keycode::Equals => volume.master = step_volume(volume.master, true),
+                                Keycode::LeftBracket => volume.sfx = step_volume(volume.sfx, false),
+                                Keycode::RightBracket => volume.sfx = step_volume(volume.sfx, true),
+                                _ => volume.muted = !volume.muted,
+                            }
+                            audio.set_volume(volume);
+                            settings.volume = volume;
+                            if let Err(e) = settings.save() {
+                                println!("cannot save settings: {}", e);
+                            }
+                            toasts.push(Toast::message(
+                                &format!("VOLUME: {}", volume.summary()),
+                                "- = master, [ ] effects, M to mute",
+                            ));
+                        }
+                        Keycode::F11 => {
+                            settings.fullscreen = !settings.fullscreen;
+                            canvas.window_mut().set_fullscreen(if settings.fullscreen {
+                                FullscreenType::Desktop
                             } else {
-                                command = Command::NextLevel;
+                                FullscreenType::Off
+                            })?;
+                            if let Err(e) = settings.save() {
+                                println!("cannot save settings: {}", e);
                             }
                         }
-                        Keycode::H => command = Command::Left,
-                        Keycode::L => command = Command::Right,
-                        Keycode::K => command = Command::Up,
-                        Keycode::J => command = Command::Down,
-                        Keycode::Y => command = Command::UpLeft,
-                        Keycode::U => command = Command::UpRight,
-                        Keycode::B => command = Command::DownLeft,
-                        Keycode::N => command = Command::DownRight,
-                        Keycode::T => command = Command::Teleport,
-                        Keycode::Period => command = Command::Wait,
-                        _ => {
-                            game.requested_sounds.push("ng.wav");
+                        // 観戦中は操作できない
+                        _ if spectator.is_some() => {}
+                        Keycode::Return => {
+                            if let Some(session) = &session {
+                                // 通信対戦ではやり直しはできない。sharedで次のレベルに進めるのはhostだけ
+                                if game.is_clear
+                                    && (session.mode == VersusMode::Race
+                                        || session.local_player == 0)
+                                {
+                                    commands.push(Command::NextLevel);
+                                } else {
+                                    game.requested_sounds.push("ng.wav");
+                                }
+                            } else if options.daily && !game.is_clear {
+                                game.requested_sounds.push("ng.wav");
+                            } else if !game.is_clear {
+                                game = new_game(&options);
+                                scheduler.reset(Instant::now());
+                                input.clear();
+                            } else {
+                                commands.push(Command::NextLevel);
+                            }
                         }
+                        _ => match key_to_command(code, game.config.topology) {
+                            Some(key) => input.push_back(key),
+                            None => game.requested_sounds.push("ng.wav"),
+                        },
                     };
                 }
                 _ => {}
             }
         }
-        game.update(command);
-        render(&mut canvas, &game, &mut resources)?;
-
-        model::wait!(sound_wait, {
-            if !game.requested_sounds.is_empty() {
-                let sound_key = game.requested_sounds.remove(0);
-                let chunk = resources
-                    .chunks
-                    .get(&sound_key.to_string())
-                    .expect("cannot get sound");
-                sdl2::mixer::Channel::all()
-                    .play(chunk, 0)
-                    .expect("cannot play sound");
-                sound_wait = SOUND_WAIT;
+        // マウスの位置はイベントの座標でなく、ウィンドウの座標を読んで自分で論理サイズの座標に直す
+        // 拡大のしかた(整数倍・ウィンドウに合わせる)と黒帯を、描くときと同じように扱うため
+        // SDLは実際のピクセル数で倍率を決めるので、高DPIの画面ではマウスの座標もピクセルにする
+        let mouse = event_pump.mouse_state();
+        let output = canvas.output_size()?;
+        let dpi = output.0 as f32 / canvas.window().size().0.max(1) as f32;
+        let mouse_cell = layout
+            .window_to_logical(
+                output,
+                settings.scale == ScaleMode::Integer,
+                (mouse.x() as f32 * dpi) as i32,
+                (mouse.y() as f32 * dpi) as i32,
+            )
+            .and_then(|(x, y)| layout.cell_at(x, y))
+            .map(|cell| game.wrap(cell))
+            .filter(|cell| (0..FIELD_W).contains(&cell.x) && (0..FIELD_H).contains(&cell.y));
+
+        if let Some(spectator) = &mut spectator {
+            spectator.poll(&mut game);
+            // 観戦は起動時の設定でなく、届いた盤面の設定で描く。キーの割り当てはgame.configから決まる
+            let received = Layout::new(&game.config);
+            if received != layout {
+                layout = received;
+                canvas
+                    .set_logical_size(layout.width as u32, layout.height as u32)
+                    .map_err(|e| e.to_string())?;
+                canvas
+                    .window_mut()
+                    .set_minimum_size(layout.width as u32, layout.height as u32)
+                    .map_err(|e| e.to_string())?;
+            }
+            animator.observe(&game);
+            for event in std::mem::take(&mut game.events) {
+                animator.handle_event(&event);
+                particles.handle_event(&event, animator.remaining());
             }
+        }
+
+        // ためておいたキーは、前の動きを見せ終わってから1つずつ処理する
+        if !animator.is_busy() {
+            match input.pop_front() {
+                // 持っていない道具や乗っ取っていない砲台は使わない
+                Some((_, c)) if !game.is_available(c) => game.requested_sounds.push("ng.wav"),
+                // 自分の番でないプレイヤーのキーは受け付けない
+                Some((player, c)) if can_input(&session, player, &game) => commands.push(c),
+                Some(_) => game.requested_sounds.push("ng.wav"),
+                None => {}
+            }
+        }
+
+        // リアルタイムモードではロボットとレーザー砲を時計で進める
+        if game.config.realtime > 0 && spectator.is_none() {
+            let now = Instant::now();
+            let robot_interval = Duration::from_millis(game.robot_interval_ms() as u64);
+            let laser_interval = Duration::from_millis(REALTIME_LASER_INTERVAL_MS as u64);
+            scheduler.set(Command::RobotTick, robot_interval, now);
+            scheduler.set(Command::LaserTick, laser_interval, now);
+            // クリア画面やゲームオーバー画面では時計を止めておく
+            if game.is_clear || game.is_over {
+                scheduler.reset(now);
+            }
+            commands.extend(scheduler.due(now));
+        }
+        // コマンドがなくてもフレームは進める
+        if commands.is_empty() {
+            commands.push(Command::None);
+        }
+
+        for command in commands {
+            let history_len = game.history.len();
+            let was_over = game.is_over;
+            game.update(command);
+            // デイリーはゲームオーバーになったときに保存する。途中でやめたときは終了するときに保存する
+            if options.daily && game.is_over && !was_over {
+                save_daily(&today, &game);
+            }
+            if let Some(session) = &mut session {
+                if game.history.len() != history_len {
+                    session.send_command(command, &game);
+                }
+            }
+        }
+        if let Some(session) = &mut session {
+            session.poll(&mut game);
+        }
+        animator.observe(&game);
+
+        // 書き換えられた画像・音・フォントを読み直す。ゲームはそのまま続ける
+        model::wait!(watch_wait, {
+            let changed = watcher.poll(&resources.assets);
+            if !changed.is_empty() {
+                reload_assets(
+                    &mut canvas,
+                    &theme,
+                    &mut resources,
+                    &texture_creator,
+                    &ttf_context,
+                    &changed,
+                );
+                let names: Vec<&str> = changed.iter().map(|(_, name)| name.as_str()).collect();
+                println!("reloaded: {}", names.join(", "));
+                toasts.push(Toast::message(
+                    "ASSETS RELOADED",
+                    &shorten(&names.join(" ")),
+                ));
+            }
+            watch_wait = ASSET_WATCH_INTERVAL;
         });
+        // 読み込めなかったものは止めずに画面に出す
+        for warning in resources.warnings.drain(..) {
+            println!("warning: {}", warning);
+            toasts.push(Toast::message("ASSET WARNING", &shorten(&warning)));
+        }
+        if let Some(server) = &server {
+            if !game.events.is_empty() {
+                server.send(&game);
+            }
+        }
+        // sharedの通信対戦では、相手に得点が入るロボットは自分の記録にしない。観戦中は何も記録しない
+        let local_player = session
+            .as_ref()
+            .filter(|s| s.mode == VersusMode::Shared)
+            .map(|s| s.local_player);
+        let mut save_profile = false;
+        for event in std::mem::take(&mut game.events) {
+            animator.handle_event(&event);
+            particles.handle_event(&event, animator.remaining());
+            if spectator.is_some() {
+                continue;
+            }
+            let unlocked = profile.handle_event(&event, local_player);
+            save_profile |= !unlocked.is_empty() || profile::is_checkpoint(&event);
+            toasts.extend(unlocked.into_iter().map(Toast::new));
+        }
+        if save_profile {
+            if let Err(e) = profile.save() {
+                println!("cannot save profile: {}", e);
+            }
+        }
+        for toast in &mut toasts {
+            toast.frames -= 1;
+        }
+        toasts.retain(|t| t.frames > 0);
+
+        let status = match (&session, &spectator) {
+            _ if game.script_error.is_some() => game.script_error.as_deref().map(script_status),
+            _ if game.level_warning.is_some() => game.level_warning.as_deref().map(warning_status),
+            (Some(session), _) => Some(session.status_line()),
+            (_, Some(spectator)) => Some(spectator.status_line()),
+            _ => None,
+        };
+        // 通信対戦では自分のプレイヤーの視界だけを描く
+        // 霧のモードのホットシートでは、番が来たプレイヤーの視界だけを描く
+        let viewer = match &session {
+            Some(session) => Some(session.local_player),
+            None if game.players.len() > 1 && !game.vision.is_empty() => Some(game.current_player),
+            None => None,
+        };
+        render(
+            &mut canvas,
+            &game,
+            &toasts,
+            status,
+            &layout,
+            viewer,
+            mouse_cell,
+            &animator,
+            &mut particles,
+            &frame_stats,
+            &theme,
+            &mut resources,
+        )?;
+        animator.advance(1 + input.len() as i32);
+        particles.advance();
+
+        // このフレームに頼まれた音はまとめて、重ねて鳴らす
+        audio.play(
+            &std::mem::take(&mut game.requested_sounds),
+            &resources.chunks,
+        );
 
         let finished = SystemTime::now();
         let elapsed = finished.duration_since(started).unwrap();
+        frame_stats.push(elapsed);
         let frame_duration = Duration::new(0, 1_000_000_000u32 / model::FPS as u32);
         if elapsed < frame_duration {
             ::std::thread::sleep(frame_duration - elapsed)
         }
     }
 
+    if let Some(session) = &mut session {
+        session.close();
+    }
+    if let Err(e) = settings.save() {
+        println!("cannot save settings: {}", e);
+    }
+    // 途中でやめたときも、それまでの記録を残す
+    if let Err(e) = profile.save() {
+        println!("cannot save profile: {}", e);
+    }
+    // デイリーは1日1回なので、1手でも動かしていればやめたところまでを結果にする
+    if options.daily && !game.is_over && !game.history.is_empty() {
+        save_daily(&today, &game);
+    }
+
     Ok(())
 }
 
-fn init_mixer() {
+// 初めて起動したときのウィンドウの大きさ。画面に収まるいちばん大きい整数倍にする
+fn initial_window_size(video: &sdl2::VideoSubsystem, layout: &Layout) -> (u32, u32) {
+    let (w, h) = (layout.width as u32, layout.height as u32);
+    let scale = match video.display_usable_bounds(0) {
+        Ok(bounds) => (bounds.width() / w).min(bounds.height() / h).max(1),
+        Err(_) => 1,
+    };
+    (w * scale, h * scale)
+}
+
+// 押されたキーのプレイヤーが今コマンドを入力できるか
+fn can_input(session: &Option<Session>, key_player: usize, game: &Game) -> bool {
+    match session {
+        // 通信対戦では自分の端末のプレイヤー1のキーだけを使う
+        Some(session) => {
+            key_player == 0
+                && match session.mode {
+                    VersusMode::Race => true,
+                    VersusMode::Shared => game.current_player == session.local_player,
+                }
+        }
+        None => key_player == game.current_player,
+    }
+}
+
+// キーとプレイヤー番号、コマンドの対応
+fn key_to_command(code: Keycode, topology: TopologyKind) -> Option<(usize, Command)> {
+    // 六角形の盤面にはUpとDownがない。2人目の斜めは w e z x にする
+    if topology == TopologyKind::Hex {
+        let result = match code {
+            Keycode::K | Keycode::J | Keycode::Q | Keycode::C => return None,
+            Keycode::W => (1, Command::UpLeft),
+            Keycode::E => (1, Command::UpRight),
+            Keycode::Z => (1, Command::DownLeft),
+            Keycode::X => (1, Command::DownRight),
+            _ => return key_to_command(code, TopologyKind::Square),
+        };
+        return Some(result);
+    }
+    let result = match code {
+        Keycode::H => (0, Command::Left),
+        Keycode::L => (0, Command::Right),
+        Keycode::K => (0, Command::Up),
+        Keycode::J => (0, Command::Down),
+        Keycode::Y => (0, Command::UpLeft),
+        Keycode::U => (0, Command::UpRight),
+        Keycode::B => (0, Command::DownLeft),
+        Keycode::N => (0, Command::DownRight),
+        Keycode::T => (0, Command::Teleport),
+        Keycode::Period => (0, Command::Wait),
+        Keycode::Num1 => (0, Command::UseItem(ItemKind::Emp)),
+        Keycode::Num2 => (0, Command::UseItem(ItemKind::JunkDropper)),
+        Keycode::Num3 => (0, Command::UseItem(ItemKind::Freeze)),
+        Keycode::Num4 => (0, Command::UseItem(ItemKind::CannonControl)),
+        Keycode::I => (0, Command::TakeCannon),
+        Keycode::O => (0, Command::RotateCannon),
+        Keycode::P => (0, Command::FireCannon),
+        Keycode::A => (1, Command::Left),
+        Keycode::D => (1, Command::Right),
+        Keycode::W => (1, Command::Up),
+        Keycode::X => (1, Command::Down),
+        Keycode::Q => (1, Command::UpLeft),
+        Keycode::E => (1, Command::UpRight),
+        Keycode::Z => (1, Command::DownLeft),
+        Keycode::C => (1, Command::DownRight),
+        Keycode::F => (1, Command::Teleport),
+        Keycode::S => (1, Command::Wait),
+        Keycode::Num7 => (1, Command::UseItem(ItemKind::Emp)),
+        Keycode::Num8 => (1, Command::UseItem(ItemKind::JunkDropper)),
+        Keycode::Num9 => (1, Command::UseItem(ItemKind::Freeze)),
+        Keycode::Num0 => (1, Command::UseItem(ItemKind::CannonControl)),
+        Keycode::R => (1, Command::TakeCannon),
+        Keycode::G => (1, Command::RotateCannon),
+        Keycode::V => (1, Command::FireCannon),
+        _ => return None,
+    };
+    Some(result)
+}
+
+// スクリプトはゲームを始めるたびに読み直すので、書き換えてからやり直せばすぐ試せる
+fn new_game(options: &Options) -> Game {
+    let seed = options.seed.unwrap_or_else(|| {
+        let seed = model::timestamp_seed();
+        println!("random seed = {}", seed);
+        seed
+    });
+    let (script, error) = match options.script.as_deref().map(Script::load) {
+        Some(Ok(script)) => (Some(script), None),
+        Some(Err(e)) => {
+            println!("script error: {}", e);
+            (None, Some(e))
+        }
+        None => (None, None),
+    };
+    let mut game = Game::with_script(seed, options.config, script);
+    if game.script_error.is_none() {
+        game.script_error = error;
+    }
+    game
+}
+
+// ボットが1つならそのまま遊ばせ、複数か--tournamentなら同じseedの組で対戦させる
+fn run_bots(options: &Options) {
+    let timeout = Duration::from_millis(options.bot_timeout);
+    if options.bots.len() == 1 && options.tournament.is_none() {
+        let result = bot::play(&options.bots[0], new_game(options), timeout, true);
+        println!("{}", bot::result_to_string(&result));
+        return;
+    }
+    let base = options.seed.unwrap_or_else(model::timestamp_seed);
+    let seeds: Vec<u64> = (0..options.tournament.unwrap_or(1))
+        .map(|i| base.wrapping_add(i))
+        .collect();
+    bot::tournament(&options.bots, &seeds, options.config, timeout);
+}
+
+fn save_daily(date: &Date, game: &Game) {
+    let result = DailyResult::from_game(date, game);
+    if let Err(e) = result.save(date) {
+        println!("cannot save daily result: {}", e);
+    }
+    if game.is_over {
+        println!("{}", result.summary());
+    }
+}
+
+fn run_solver(options: &Options) {
+    let game = new_game(options);
+    let mut config = SolverConfig::new(options.solve_depth);
+    config.allow_teleport = options.solve_teleport;
+    println!("seed = {}, level = {}", game.seed, game.level);
+    match solver::solve(&game, &config) {
+        SolveResult::Solved(commands) => {
+            // 2人のときは2人の手がそろって1ターンなので、手順をやり直してターン数を数える
+            let mut replay = game.clone();
+            for command in &commands {
+                replay.update(*command);
+            }
+            println!(
+                "solvable in {} turns ({} commands)",
+                replay.turn - game.turn,
+                commands.len()
+            );
+            println!("{}", solver::commands_to_string(&commands));
+        }
+        SolveResult::Unsolvable => {
+            println!("not solvable within {} commands", config.max_depth)
+        }
+        SolveResult::GaveUp => println!(
+            "gave up after {} states (depth {})",
+            config.node_limit, config.max_depth
+        ),
+    }
+}
+
+// 音が出せないときは警告を出して、音なしで遊べるようにする
+fn init_mixer() -> bool {
     let chunk_size = 1_024;
-    mixer::open_audio(
+    if let Err(e) = mixer::open_audio(
         mixer::DEFAULT_FREQUENCY,
         mixer::DEFAULT_FORMAT,
         mixer::DEFAULT_CHANNELS,
         chunk_size,
-    )
-    .expect("cannot open audio");
-    let _mixer_context = mixer::init(mixer::InitFlag::MP3).expect("cannot init mixer");
+    ) {
+        println!("warning: cannot open audio, playing without sound: {}", e);
+        return false;
+    }
+    if let Err(e) = mixer::init(mixer::InitFlag::MP3) {
+        println!("warning: cannot init mixer: {}", e);
+    }
+    true
 }
 
 fn load_resources<'a>(
     texture_creator: &'a TextureCreator<WindowContext>,
-    #[allow(unused_variables)] canvas: &mut Canvas<Window>,
-    ttf_context: &'a Sdl2TtfContext,
+    assets: Assets,
+    audio: bool,
 ) -> Resources<'a> {
     let mut resources = Resources {
         images: HashMap::new(),
+        sheets: HashMap::new(),
         chunks: HashMap::new(),
-        fonts: HashMap::new(),
+        glyphs: HashMap::new(),
+        atlas: None,
+        texts: TextCache::new(texture_creator),
+        assets,
+        audio,
+        warnings: Vec::new(),
     };
 
-    let entries = fs::read_dir("resources/image").unwrap();
-    for entry in entries {
-        let path = entry.unwrap().path();
-        let path_str = path.to_str().unwrap();
-        if path_str.ends_with(".bmp") {
-            let temp_surface = sdl2::surface::Surface::load_bmp(&path).unwrap();
-            let texture = texture_creator
-                .create_texture_from_surface(&temp_surface)
-                .unwrap_or_else(|_| panic!("cannot load image: {}", path_str));
+    for name in resources.assets.names(AssetKind::Image) {
+        if let Some(texture) = load_image(texture_creator, &mut resources, &name) {
+            resources.images.insert(name, texture);
+        }
+    }
+    load_sheets(&mut resources);
 
-            let basename = path.file_name().unwrap().to_str().unwrap();
-            let image = Image::new(texture);
-            resources.images.insert(basename.to_string(), image);
+    // 読めなかった音は鳴らさない
+    if audio {
+        for name in resources.assets.names(AssetKind::Sound) {
+            if let Some(chunk) = load_sound(&mut resources, &name) {
+                resources.chunks.insert(name, chunk);
+            }
         }
     }
 
-    let entries = fs::read_dir("./resources/sound").unwrap();
-    for entry in entries {
-        let path = entry.unwrap().path();
-        let path_str = path.to_str().unwrap();
-        if path_str.ends_with(".wav") {
-            let chunk = mixer::Chunk::from_file(path_str)
-                .unwrap_or_else(|_| panic!("cannot load sound: {}", path_str));
-            let basename = path.file_name().unwrap().to_str().unwrap();
-            resources.chunks.insert(basename.to_string(), chunk);
+    resources
+}
+
+// テーマの画像があるかを確かめてから、フォントと文字の画像を作り直す
+fn apply_theme<'a>(
+    canvas: &mut Canvas<Window>,
+    theme: &Theme,
+    resources: &mut Resources<'a>,
+    texture_creator: &'a TextureCreator<WindowContext>,
+    ttf_context: &'a Sdl2TtfContext,
+) -> Result<(), String> {
+    // 見つからない画像は代わりの模様で描く
+    for sprite in theme.sprites() {
+        if !resources.images.contains_key(sprite) {
+            resources.warnings.push(format!(
+                "theme {}: sprite not found: {}, drawing a placeholder",
+                theme.name, sprite
+            ));
+            let texture = placeholder_texture(texture_creator)?;
+            resources.images.insert(sprite.to_string(), texture);
         }
     }
+    let mut load_font = |name: &str| {
+        load_asset(
+            &resources.assets,
+            &mut resources.warnings,
+            AssetKind::Font,
+            name,
+            |rwops| ttf_context.load_font_from_rwops(rwops, theme.font_size),
+        )
+    };
+    // テーマのフォントが読めなければ埋め込んであるフォントにする
+    let font = load_font(&theme.font)
+        .or_else(|| load_font(DEFAULT_FONT))
+        .ok_or_else(|| format!("theme {}: cannot load font {}", theme.name, theme.font))?;
+    let mut glyphs = HashMap::new();
+    for c in theme.glyphs() {
+        // 白で作っておき、描くときに色を付ける
+        let surface = font
+            .render_char(c)
+            .blended(Color::RGB(255, 255, 255))
+            .map_err(|e| format!("theme {}: cannot render glyph {}: {}", theme.name, c, e))?;
+        let mut texture = texture_creator
+            .create_texture_from_surface(&surface)
+            .map_err(|e| e.to_string())?;
+        texture.set_blend_mode(BlendMode::Blend);
+        glyphs.insert(c, texture);
+    }
+    resources.texts.set_font(font);
+    resources.glyphs = glyphs;
+    build_atlas(canvas, texture_creator, resources)
+}
 
-    load_font(
-        &mut resources,
-        ttf_context,
-        "./resources/font/boxfont2.ttf",
-        24,
-        "boxfont",
+// 見つからない画像の代わり。白と灰色の市松模様にして、描くときに色を付ける
+fn placeholder_texture(
+    texture_creator: &TextureCreator<WindowContext>,
+) -> Result<Texture<'_>, String> {
+    let mut surface = Surface::new(16, 16, PixelFormatEnum::RGBA8888)?;
+    for y in 0..4 {
+        for x in 0..4 {
+            let c = if (x + y) % 2 == 0 {
+                Color::RGB(255, 255, 255)
+            } else {
+                Color::RGB(96, 96, 96)
+            };
+            surface.fill_rect(Rect::new(x * 4, y * 4, 4, 4), c)?;
+        }
+    }
+    let mut texture = texture_creator
+        .create_texture_from_surface(&surface)
+        .map_err(|e| e.to_string())?;
+    texture.set_blend_mode(BlendMode::Blend);
+    Ok(texture)
+}
+
+// 候補を順に試して、最初に読めたものを使う。読めなかったものには警告を出す
+fn load_asset<T>(
+    assets: &Assets,
+    warnings: &mut Vec<String>,
+    kind: AssetKind,
+    name: &str,
+    mut load: impl FnMut(RWops<'static>) -> Result<T, String>,
+) -> Option<T> {
+    for source in assets.sources(kind, name) {
+        let rwops = match &source {
+            AssetSource::File(path) => RWops::from_file(path, "rb"),
+            AssetSource::Embedded(data) => RWops::from_bytes(data),
+        };
+        match rwops.and_then(&mut load) {
+            Ok(value) => return Some(value),
+            Err(e) => warnings.push(format!("cannot load {}: {}", source, e)),
+        }
+    }
+    warnings.push(format!("{:?} not found: {}", kind, name));
+    None
+}
+
+fn load_image<'a>(
+    texture_creator: &'a TextureCreator<WindowContext>,
+    resources: &mut Resources<'a>,
+    name: &str,
+) -> Option<Texture<'a>> {
+    let texture = load_asset(
+        &resources.assets,
+        &mut resources.warnings,
+        AssetKind::Image,
+        name,
+        |rwops| {
+            // BMPかPNGかは中身で決まる
+            let surface = rwops.load()?;
+            texture_creator
+                .create_texture_from_surface(&surface)
+                .map_err(|e| e.to_string())
+        },
     );
+    texture.map(|mut texture| {
+        // テレポートのときに透明にして描く
+        texture.set_blend_mode(BlendMode::Blend);
+        texture
+    })
+}
 
-    resources
+// スプライトシートを読み、画像の大きさに合わせてコマに分ける。画像が変わったときも読み直す
+fn load_sheets(resources: &mut Resources) {
+    let mut sheets = HashMap::new();
+    for name in resources.assets.names(AssetKind::Sheet) {
+        let sheet = load_asset(
+            &resources.assets,
+            &mut resources.warnings,
+            AssetKind::Sheet,
+            &name,
+            |mut rwops| {
+                let mut text = String::new();
+                rwops.read_to_string(&mut text).map_err(|e| e.to_string())?;
+                SpriteSheet::parse(&name, &text)
+            },
+        );
+        let Some(mut sheet) = sheet else {
+            continue;
+        };
+        let Some(texture) = resources.images.get(&sheet.image) else {
+            resources
+                .warnings
+                .push(format!("sheet {}: image not found: {}", name, sheet.image));
+            continue;
+        };
+        let query = texture.query();
+        match sheet.resolve(query.width, query.height) {
+            Ok(()) => {
+                sheets.insert(sheet.image.clone(), sheet);
+            }
+            Err(e) => resources.warnings.push(format!("sheet {}: {}", name, e)),
+        }
+    }
+    resources.sheets = sheets;
+}
+
+fn load_sound(resources: &mut Resources, name: &str) -> Option<mixer::Chunk> {
+    load_asset(
+        &resources.assets,
+        &mut resources.warnings,
+        AssetKind::Sound,
+        name,
+        |rwops| rwops.load_wav(),
+    )
 }
 
-fn load_font<'a>(
+// 書き換えられたファイルを読み直す。ゲームの状態はそのままで、読めなければ前のものを使い続ける
+fn reload_assets<'a>(
+    canvas: &mut Canvas<Window>,
+    theme: &Theme,
     resources: &mut Resources<'a>,
+    texture_creator: &'a TextureCreator<WindowContext>,
     ttf_context: &'a Sdl2TtfContext,
-    path_str: &str,
-    point_size: u16,
-    key: &str,
+    changed: &[(AssetKind, String)],
 ) {
-    let font = ttf_context
-        .load_font(path_str, point_size)
-        .unwrap_or_else(|_| panic!("cannot load font: {}", path_str));
-    resources.fonts.insert(key.to_string(), font);
+    let mut atlas = false;
+    let mut sheets = false;
+    let mut font = false;
+    for (kind, name) in changed {
+        match kind {
+            AssetKind::Image => {
+                if let Some(texture) = load_image(texture_creator, resources, name) {
+                    resources.images.insert(name.clone(), texture);
+                    atlas = true;
+                    sheets = true;
+                }
+            }
+            AssetKind::Sheet => sheets = true,
+            AssetKind::Sound if resources.audio => {
+                if let Some(chunk) = load_sound(resources, name) {
+                    resources.chunks.insert(name.clone(), chunk);
+                }
+            }
+            AssetKind::Sound => {}
+            AssetKind::Font => font = true,
+        }
+    }
+    // コマの数は画像の大きさで変わる
+    if sheets {
+        load_sheets(resources);
+    }
+    // フォントが変わったら文字の画像も作り直すので、アトラスも一緒に作り直される
+    let result = if font {
+        apply_theme(canvas, theme, resources, texture_creator, ttf_context)
+    } else if atlas {
+        build_atlas(canvas, texture_creator, resources)
+    } else {
+        Ok(())
+    };
+    if let Err(e) = result {
+        resources.warnings.push(e);
+    }
+}
+
+// 画像とテーマの文字を1枚にまとめる。描画先のテクスチャが消えたときにも作り直す
+fn build_atlas<'a>(
+    canvas: &mut Canvas<Window>,
+    texture_creator: &'a TextureCreator<WindowContext>,
+    resources: &mut Resources<'a>,
+) -> Result<(), String> {
+    let images = resources
+        .images
+        .iter_mut()
+        .map(|(name, texture)| (SpriteKey::Image(name.clone()), texture));
+    let glyphs = resources
+        .glyphs
+        .iter_mut()
+        .map(|(c, texture)| (SpriteKey::Glyph(*c), texture));
+    let atlas = Atlas::build(canvas, texture_creator, images.chain(glyphs).collect())?;
+    resources.atlas = Some(atlas);
+    Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn render(
     canvas: &mut Canvas<Window>,
     game: &Game,
+    toasts: &[Toast],
+    status: Option<String>,
+    layout: &Layout,
+    viewer: Option<usize>,
+    mouse_cell: Option<Vec2>,
+    animator: &Animator,
+    particles: &mut Particles,
+    frame_stats: &FrameStats,
+    theme: &Theme,
     resources: &mut Resources,
 ) -> Result<(), String> {
-    canvas.set_draw_color(Color::RGB(32, 32, 32));
+    canvas.set_draw_color(color(theme.background));
     canvas.clear();
 
-    let font = resources.fonts.get_mut("boxfont").unwrap();
+    // プレイヤーが死んだときは盤面を揺らす
+    let (shake_x, shake_y) = particles.shake_offset();
+    canvas.set_viewport(Rect::new(
+        shake_x,
+        shake_y,
+        layout.width as u32,
+        layout.height as u32,
+    ));
 
     // render junks
+    // 六角形の盤面はマス目を描く
+    // 塗るものはまとめて描く。重なり方が変わらないように、種類ごとにflushする
+    let mut batch = Batch::default();
+    if layout.hex {
+        for y in -layout.margin..FIELD_H + layout.margin {
+            for x in -layout.margin..FIELD_W + layout.margin {
+                batch.fill(color(theme.grid), layout.cell_rows(Vec2 { x, y }));
+            }
+        }
+        batch.flush(canvas)?;
+    }
+
+    let outline = theme.outline.map(color);
+    let idle = Pose::new(AnimKind::Idle, animator.ms());
     for junk in &game.junks {
-        canvas.set_draw_color(Color::RGB(128, 128, 128));
-        canvas.fill_rect(Rect::new(
-            junk.pos.x * CELL_W,
-            junk.pos.y * CELL_H + INFO_HEIGHT,
-            CELL_W as u32,
-            CELL_H as u32,
-        ))?;
+        if animator.hides_junk(junk.pos) || !game.is_visible(viewer, junk.pos) {
+            continue;
+        }
+        for pos in layout.copies(junk.pos) {
+            let rect = layout.cell_rect(pos);
+            draw_look(
+                canvas,
+                &mut batch,
+                layout,
+                resources,
+                &theme.junk_look,
+                color(theme.junk),
+                rect,
+                idle,
+                outline,
+            )?;
+        }
     }
+    batch.flush(canvas)?;
 
-    // render player
-    let image = resources.images.get("hito.bmp").unwrap();
-    canvas
-        .copy(
-            &image.texture,
-            Rect::new(0, 0, 16, 16),
-            Rect::new(
-                game.player.pos.x * CELL_W,
-                game.player.pos.y * CELL_H + INFO_HEIGHT,
-                CELL_W as u32,
-                CELL_H as u32,
-            ),
-        )
-        .unwrap();
+    // render items
+    for item in &game.items {
+        if !game.is_visible(viewer, item.pos) {
+            continue;
+        }
+        let item_color = color(theme.item(item.kind));
+        for pos in layout.copies(item.pos) {
+            let rect = layout.cell_rect(pos);
+            match &theme.item_look {
+                Look::Cell => {
+                    batch.fill(item_color, [Rect::new(rect.x() + 4, rect.y() + 4, 8, 8)]);
+                    batch.frame(
+                        color(theme.text),
+                        Rect::new(rect.x() + 3, rect.y() + 3, 10, 10),
+                    );
+                }
+                Look::Letters => {
+                    let look = Look::Glyph(item.kind.letter());
+                    draw_look(
+                        canvas, &mut batch, layout, resources, &look, item_color, rect, idle,
+                        outline,
+                    )?;
+                }
+                look => draw_look(
+                    canvas, &mut batch, layout, resources, look, item_color, rect, idle, outline,
+                )?,
+            }
+        }
+    }
+    batch.flush(canvas)?;
+
+    // render players
+    for (i, player) in game.players.iter().enumerate() {
+        // 霧のモードでは他のプレイヤーも見えているときだけ描く
+        if !player.alive || !game.is_visible(viewer, player.pos) {
+            continue;
+        }
+        let player_color = color(theme.players[i]);
+        // テレポートは元の位置で消えてから行き先に現れる
+        let (prev_pos, alpha) = match animator.teleport(i) {
+            Some((from, _)) if animator.progress() < 0.5 => (from, 1.0 - animator.progress() * 2.0),
+            Some((_, to)) => (to, animator.progress() * 2.0 - 1.0),
+            None => (player.prev_pos, 1.0),
+        };
+        let pos = if alpha < 1.0 { prev_pos } else { player.pos };
+        let fading = Color::RGBA(
+            player_color.r,
+            player_color.g,
+            player_color.b,
+            (alpha * 255.0) as u8,
+        );
+        let pose = Pose::new(walk_or_idle(animator, prev_pos, pos), animator.ms());
+        for copy in layout.copies(pos) {
+            let rect = tween_rect(layout, game, animator, prev_pos, pos, copy);
+            draw_look(
+                canvas,
+                &mut batch,
+                layout,
+                resources,
+                &theme.player_look,
+                fading,
+                rect,
+                pose,
+                outline,
+            )?;
+            batch.flush(canvas)?;
+            // 2人のときは次に動くプレイヤーを枠で示す
+            if game.players.len() > 1 && i == game.current_player && !game.is_clear {
+                canvas.set_draw_color(player_color);
+                canvas.draw_rect(rect)?;
+            }
+        }
+    }
 
     // render robots
-    for robot in &game.robots {
-        let color = Color::RGB(255, 128, 128);
-        canvas.set_draw_color(color);
-        canvas.fill_rect(Rect::new(
-            robot.pos.x * CELL_W,
-            robot.pos.y * CELL_H + INFO_HEIGHT,
-            CELL_W as u32,
-            CELL_H as u32,
-        ))?;
+    // 止まっているロボットは色を変える
+    let robot_color = if game.freeze > 0 {
+        color(theme.robot_frozen)
+    } else {
+        color(theme.robot)
+    };
+    let moving = game.robots.iter().map(|r| (r.prev_pos, r.pos));
+    for (prev_pos, pos) in moving.chain(animator.wrecks().iter().copied()) {
+        if !game.is_visible(viewer, pos) {
+            continue;
+        }
+        let pose = Pose::new(walk_or_idle(animator, prev_pos, pos), animator.ms());
+        for copy in layout.copies(pos) {
+            let rect = tween_rect(layout, game, animator, prev_pos, pos, copy);
+            draw_look(
+                canvas,
+                &mut batch,
+                layout,
+                resources,
+                &theme.robot_look,
+                robot_color,
+                rect,
+                pose,
+                outline,
+            )?;
+        }
     }
+    // 壊れたロボットと死んだプレイヤーは、deathのアニメーションがある画像ならしばらく見せる
+    for death in &animator.deaths {
+        let (look, death_color) = match death.player {
+            Some(player) => (&theme.player_look, color(theme.players[player])),
+            None => (&theme.robot_look, robot_color),
+        };
+        if death.age < 0
+            || !game.is_visible(viewer, death.pos)
+            || !has_animation(resources, look, AnimKind::Death)
+        {
+            continue;
+        }
+        let pose = Pose::new(AnimKind::Death, death.ms());
+        for pos in layout.copies(death.pos) {
+            let rect = layout.cell_rect(pos);
+            draw_look(
+                canvas,
+                &mut batch,
+                layout,
+                resources,
+                look,
+                death_color,
+                rect,
+                pose,
+                outline,
+            )?;
+        }
+    }
+    // 霧の中のロボットは最後に見た位置に枠だけ描く
+    for robot in game.remembered_robots(viewer) {
+        for pos in layout.copies(robot) {
+            batch.frame(color(theme.robot_remembered), layout.cell_rect(pos));
+        }
+    }
+    batch.flush(canvas)?;
 
     // render laser cannon
-    let laser_cannon_image = resources.images.get_mut("laser_cannon.bmp").unwrap();
-    canvas
-        .copy_ex(
-            &laser_cannon_image.texture,
-            None,
-            Rect::new(
-                game.laser_cannon.pos.x * CELL_W,
-                game.laser_cannon.pos.y * CELL_H + INFO_HEIGHT,
-                CELL_W as u32,
-                CELL_H as u32,
-            ),
-            match game.laser_cannon.direction {
-                Direction::Left => -90.0,
-                Direction::Right => 90.0,
-                Direction::Up => 0.0,
-                Direction::Down => 180.0,
-                _ => panic!(),
-            }, /* SDLのangleは時計回りが正 */
-            Point::new(CELL_W / 2, CELL_H / 2),
-            false,
-            false,
-        )
-        .unwrap();
+    let cannon_copies = if game.is_cannon_seen(viewer) {
+        layout.copies(game.laser_cannon.pos)
+    } else {
+        Vec::new()
+    };
+    // 前の向きから回して見せる。回る向きは近いほう
+    let from = cannon_angle(game.laser_cannon.prev_direction, layout.hex);
+    let to = cannon_angle(game.laser_cannon.direction, layout.hex);
+    let angle = from + ((to - from + 540.0) % 360.0 - 180.0) * animator.progress();
+    // 回っている間はwalkのアニメーションにする
+    let turning = game.laser_cannon.prev_direction != game.laser_cannon.direction;
+    let pose = Pose {
+        anim: if turning && animator.is_busy() {
+            AnimKind::Walk
+        } else {
+            AnimKind::Idle
+        },
+        ms: animator.ms(),
+        angle,
+    };
+    // 乗っ取られている砲台はそのプレイヤーの色にする。撃てないうちは暗くする
+    let cannon_color = match game.laser_cannon.controller {
+        Some(player) if game.laser_cannon.cooldown > 0 => {
+            let c = theme.players[player];
+            Color::RGB(c.0 / 2, c.1 / 2, c.2 / 2)
+        }
+        Some(player) => color(theme.players[player]),
+        None => color(theme.cannon),
+    };
+    for pos in cannon_copies {
+        let rect = layout.cell_rect(pos);
+        draw_look(
+            canvas,
+            &mut batch,
+            layout,
+            resources,
+            &theme.cannon_look,
+            cannon_color,
+            rect,
+            pose,
+            outline,
+        )?;
+        batch.flush(canvas)?;
+        if let Some(player) = game.laser_cannon.controller {
+            canvas.set_draw_color(color(theme.players[player]));
+            canvas.draw_rect(rect)?;
+        }
+    }
 
     // render laser
-    if game.laser_cannon.is_shooting {
-        let laser_color = Color::RGB(255, 255, 128);
-        canvas.set_draw_color(laser_color);
-        if game.laser_cannon.direction == Direction::Left
-            || game.laser_cannon.direction == Direction::Right
-        {
-            canvas.fill_rect(Rect::new(
-                game.laser_cannon.begin * CELL_W,
-                game.laser_cannon.pos.y * CELL_H + INFO_HEIGHT + 6,
-                ((game.laser_cannon.end - game.laser_cannon.begin) * CELL_W) as u32,
-                4,
-            ))?;
-        } else {
-            canvas.fill_rect(Rect::new(
-                game.laser_cannon.pos.x * CELL_W + 6,
-                game.laser_cannon.begin * CELL_H + INFO_HEIGHT,
-                4,
-                ((game.laser_cannon.end - game.laser_cannon.begin) * CELL_H) as u32,
-            ))?;
+    // 砲台から伸びてから消えていく
+    let (reach, alpha) = animator.laser();
+    if game.laser_cannon.is_shooting && alpha > 0 {
+        let laser = theme.laser;
+        canvas.set_draw_color(Color::RGBA(laser.0, laser.1, laser.2, alpha));
+        let beam = &game.laser_cannon.beam;
+        let length = (beam.len() as f64 * reach).ceil() as usize;
+        let horizontal = game.laser_cannon.direction == Direction::Left
+            || game.laser_cannon.direction == Direction::Right;
+        let vertical = game.laser_cannon.direction == Direction::Up
+            || game.laser_cannon.direction == Direction::Down;
+        let width = theme.laser_width;
+        for cell in &beam[..length] {
+            if !game.is_visible(viewer, *cell) {
+                continue;
+            }
+            for pos in layout.copies(*cell) {
+                let rect = layout.cell_rect(pos);
+                if !horizontal && !vertical {
+                    // 斜めのレーザーはマスの中心に点を描く。破線のときは中を抜く
+                    let dot = Rect::from_center(rect.center(), width + 2, width + 2);
+                    if theme.laser_dashed {
+                        canvas.draw_rect(dot)?;
+                    } else {
+                        canvas.fill_rect(dot)?;
+                    }
+                } else if theme.laser_dashed {
+                    // 1マスに2本の短い線を描く
+                    for side in [-1, 1] {
+                        let (dash, center) = if horizontal {
+                            (
+                                (CELL_W as u32 / 2 - 2, width),
+                                rect.center().offset(side * CELL_W / 4, 0),
+                            )
+                        } else {
+                            (
+                                (width, CELL_H as u32 / 2 - 2),
+                                rect.center().offset(0, side * CELL_H / 4),
+                            )
+                        };
+                        canvas.fill_rect(Rect::from_center(center, dash.0, dash.1))?;
+                    }
+                } else if horizontal {
+                    canvas.fill_rect(Rect::from_center(rect.center(), CELL_W as u32, width))?;
+                } else {
+                    canvas.fill_rect(Rect::from_center(rect.center(), width, CELL_H as u32))?;
+                }
+            }
         }
     }
 
+    // 壊れたロボットの爆発。ジャンクになったときは大きい
+    let flash = theme.explosion;
+    for explosion in &animator.explosions {
+        if explosion.age < 0 || !game.is_visible(viewer, explosion.pos) {
+            continue;
+        }
+        let t = explosion.age as f64 / animation::EXPLOSION_FRAMES as f64;
+        let max = if explosion.big { CELL_W * 2 } else { CELL_W };
+        let size = (4.0 + (max - 4) as f64 * t) as i32;
+        let alpha = (255.0 * (1.0 - t)) as u8;
+        for pos in layout.copies(explosion.pos) {
+            let center = layout.cell_rect(pos).center();
+            canvas.set_draw_color(Color::RGBA(flash.0, flash.1, flash.2, alpha));
+            canvas.fill_rect(Rect::from_center(center, size as u32, size as u32))?;
+            // 中心は白に近づける
+            canvas.set_draw_color(Color::RGBA(
+                flash.0 / 2 + 128,
+                flash.1 / 2 + 128,
+                flash.2 / 2 + 128,
+                alpha,
+            ));
+            canvas.fill_rect(Rect::from_center(center, size as u32 / 2, size as u32 / 2))?;
+        }
+    }
+
+    // render particles
+    for particle in &particles.particles {
+        if particle.is_waiting() || !game.is_visible(viewer, particle.cell) {
+            continue;
+        }
+        let alpha = (255.0 * particle.fade()) as u8;
+        let (color, size) = match particle.kind {
+            ParticleKind::Spark => (theme.spark, 2),
+            ParticleKind::Debris => (theme.debris, 3),
+            ParticleKind::Scorch => (theme.scorch, 2),
+        };
+        let color = Color::RGBA(color.0, color.1, color.2, alpha);
+        for pos in layout.copies(particle.cell) {
+            let center = layout.cell_rect(pos).center();
+            batch.fill(
+                color,
+                [Rect::new(
+                    center.x() + particle.dx as i32,
+                    center.y() + particle.dy as i32,
+                    size,
+                    size,
+                )],
+            );
+        }
+    }
+    batch.flush(canvas)?;
+
+    // 霧のモードで見えていないマス
+    if !game.vision.is_empty() {
+        for y in -layout.margin..FIELD_H + layout.margin {
+            for x in -layout.margin..FIELD_W + layout.margin {
+                let pos = Vec2 { x, y };
+                if !game.is_visible(viewer, game.wrap(pos)) {
+                    batch.fill(Color::RGBA(0, 0, 0, 200), layout.cell_rows(pos));
+                }
+            }
+        }
+        batch.flush(canvas)?;
+    }
+
+    // 余白のゴーストは暗くする
+    if layout.margin > 0 {
+        let (mw, mh) = (layout.margin * CELL_W, layout.margin * CELL_H);
+        let (fw, fh) = (layout.width - mw * 2, FIELD_H * CELL_H);
+        canvas.set_draw_color(Color::RGBA(0, 0, 0, 160));
+        canvas.fill_rects(&[
+            Rect::new(0, INFO_HEIGHT, layout.width as u32, mh as u32),
+            Rect::new(0, INFO_HEIGHT + mh + fh, layout.width as u32, mh as u32),
+            Rect::new(0, INFO_HEIGHT + mh, mw as u32, fh as u32),
+            Rect::new(mw + fw, INFO_HEIGHT + mh, mw as u32, fh as u32),
+        ])?;
+    }
+
     // render info
-    canvas.set_draw_color(Color::RGB(0, 0, 0));
-    canvas.fill_rect(Rect::new(0, 0, SCREEN_WIDTH as u32, INFO_HEIGHT as u32))?;
-
-    let font_color = Color::RGB(224, 224, 224);
-    render_font(
-        canvas,
-        font,
-        format!(
-            "LEVEL {}   ROBOTS {}/{}",
-            game.level,
-            game.robots.len(),
-            game.initial_robot_count
-        )
-        .to_string(),
-        0,
-        0,
-        font_color,
-        false,
-    );
+    canvas.set_viewport(None);
+    canvas.set_draw_color(color(theme.hud));
+    canvas.fill_rect(Rect::new(0, 0, layout.width as u32, INFO_HEIGHT as u32))?;
+
+    let texts = &mut resources.texts;
+    let font_color = color(theme.text);
+    if game.players.len() > 1 {
+        render_font(
+            canvas,
+            texts,
+            format!("LV {}  ROBOTS {}", game.level, game.robots_left()),
+            0,
+            0,
+            font_color,
+            false,
+        );
+        for (i, player) in game.players.iter().enumerate() {
+            render_inventory(
+                canvas,
+                theme,
+                &player.items,
+                layout.width / 2 - 68 + i as i32 * 78,
+            )?;
+            render_font(
+                canvas,
+                texts,
+                format!("P{} {}", i + 1, player.score),
+                layout.width - 200 + i as i32 * 100,
+                0,
+                if player.alive {
+                    color(theme.players[i])
+                } else {
+                    Color::RGB(96, 96, 96)
+                },
+                false,
+            );
+        }
+    } else {
+        render_font(
+            canvas,
+            texts,
+            format!(
+                "LEVEL {}   ROBOTS {}   TURN {}",
+                game.level,
+                game.robots_left(),
+                game.stats.turns
+            )
+            .to_string(),
+            0,
+            0,
+            font_color,
+            false,
+        );
+        let items = &game.players[0].items;
+        let count = items.len().min(INVENTORY_MAX) as i32;
+        render_inventory(canvas, theme, items, layout.width - count * 12 - 4)?;
+    }
 
     if game.is_over {
-        canvas.set_draw_color(Color::RGBA(255, 0, 0, 128));
-        canvas.fill_rect(Rect::new(0, 0, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32))?;
+        let c = theme.game_over;
+        canvas.set_draw_color(Color::RGBA(c.0, c.1, c.2, 128));
+        canvas.fill_rect(Rect::new(0, 0, layout.width as u32, layout.height as u32))?;
+        render_font(
+            canvas,
+            texts,
+            "GAME OVER".to_string(),
+            layout.width / 2,
+            layout.height / 2 - 80,
+            Color::RGB(255, 255, 255),
+            true,
+        );
+        render_summary(canvas, texts, game, layout, font_color)?;
     }
 
     if game.is_clear {
-        let colors = [
-            Color::RGB(255, 128, 128),
-            Color::RGB(128, 255, 128),
-            Color::RGB(128, 128, 255),
-            Color::RGB(255, 255, 128),
-            Color::RGB(128, 255, 255),
-            Color::RGB(255, 128, 255),
-        ];
-        let color = colors[game.frame as usize % colors.len()];
+        let color = color(theme.win[game.frame as usize % theme.win.len()]);
         render_font(
             canvas,
-            font,
+            texts,
             "YOU WIN!".to_string(),
-            SCREEN_WIDTH / 2,
-            SCREEN_HEIGHT / 2 - 20,
+            layout.width / 2,
+            layout.height / 2 - 20,
             color,
             true,
         );
         render_font(
             canvas,
-            font,
+            texts,
             "PRESS ENTER TO NEXT LEVEL".to_string(),
-            SCREEN_WIDTH / 2,
-            SCREEN_HEIGHT / 2 + 20,
+            layout.width / 2,
+            layout.height / 2 + 20,
             Color::RGB(255, 255, 255),
             true,
         );
+        render_summary(canvas, texts, game, layout, font_color)?;
+    }
+
+    // 通信対戦の状態やスクリプトのエラー
+    if let Some(status) = status {
+        canvas.set_draw_color(Color::RGBA(0, 0, 0, 192));
+        canvas.fill_rect(Rect::new(0, layout.height - 28, layout.width as u32, 28))?;
+        render_font(
+            canvas,
+            texts,
+            status,
+            layout.width / 2,
+            layout.height - 28,
+            font_color,
+            true,
+        );
+    }
+
+    render_toasts(canvas, texts, toasts, layout, theme)?;
+
+    if frame_stats.visible {
+        let lines = [
+            frame_stats.summary(),
+            format!(
+                "ROBOTS {}  JUNK {}  TEXTS {}",
+                game.robots.len(),
+                game.junks.len(),
+                texts.len()
+            ),
+            match mouse_cell {
+                Some(cell) => format!("MOUSE {} {}", cell.x, cell.y),
+                None => "MOUSE -".to_string(),
+            },
+        ];
+        render_overlay(canvas, texts, &lines, INFO_HEIGHT + 4)?;
     }
 
     canvas.present();
+    resources.texts.end_frame();
+
+    Ok(())
+}
 
+// 盤面の左上に暗い帯をしいて文字を並べる
+fn render_overlay(
+    canvas: &mut Canvas<Window>,
+    texts: &mut TextCache,
+    lines: &[String],
+    y: i32,
+) -> Result<(), String> {
+    let mut y = y;
+    for line in lines {
+        let texture = texts.get(line, Color::RGB(255, 255, 255))?;
+        let q = texture.query();
+        canvas.set_draw_color(Color::RGBA(0, 0, 0, 192));
+        canvas.fill_rect(Rect::new(0, y, q.width + 8, q.height))?;
+        canvas.copy(texture, None, Rect::new(4, y, q.width, q.height))?;
+        y += q.height as i32;
+    }
+    Ok(())
+}
+
+// prevから今の位置へ動いている途中の矩形。copyはposの写しで、prevも同じだけずらす
+fn tween_rect(
+    layout: &Layout,
+    game: &Game,
+    animator: &Animator,
+    prev: Vec2,
+    pos: Vec2,
+    copy: Vec2,
+) -> Rect {
+    let to = layout.cell_rect(copy);
+    if prev == pos {
+        return to;
+    }
+    // トーラスの端を越えたときは、越えた先から入ってくるように見せる
+    let from = layout.cell_rect(game.nearest_copy(pos, prev) + (copy - pos));
+    let t = animator.progress();
+    let lerp = |a: i32, b: i32| a + ((b - a) as f64 * t).round() as i32;
+    Rect::new(
+        lerp(from.x(), to.x()),
+        lerp(from.y(), to.y()),
+        to.width(),
+        to.height(),
+    )
+}
+
+// prevから動いている途中ならwalk、止まっていればidle
+fn walk_or_idle(animator: &Animator, prev: Vec2, pos: Vec2) -> AnimKind {
+    if prev != pos && animator.is_busy() {
+        AnimKind::Walk
+    } else {
+        AnimKind::Idle
+    }
+}
+
+// SDLのangleは時計回りが正。斜めの角度は六角形の盤面では60度おき
+fn cannon_angle(direction: Direction, hex: bool) -> f64 {
+    let diagonal = if hex { 30.0 } else { 45.0 };
+    match direction {
+        Direction::Left => -90.0,
+        Direction::Right => 90.0,
+        Direction::Up => 0.0,
+        Direction::Down => 180.0,
+        Direction::UpRight => diagonal,
+        Direction::DownRight => 180.0 - diagonal,
+        Direction::DownLeft => diagonal - 180.0,
+        Direction::UpLeft => -diagonal,
+    }
+}
+
+fn color(rgb: Rgb) -> Color {
+    Color::RGB(rgb.0, rgb.1, rgb.2)
+}
+
+// スプライトシートのどのアニメーションを、始まってから何ミリ秒のところで描くか。画像はangleで回す
+#[derive(Clone, Copy)]
+struct Pose {
+    anim: AnimKind,
+    ms: u32,
+    angle: f64,
+}
+
+impl Pose {
+    fn new(anim: AnimKind, ms: u32) -> Self {
+        Pose {
+            anim,
+            ms,
+            angle: 0.0,
+        }
+    }
+}
+
+// lookがanimのアニメーションを持つ画像か
+fn has_animation(resources: &Resources, look: &Look, anim: AnimKind) -> bool {
+    match look {
+        Look::Sprite(file) => resources.sheets.get(file).is_some_and(|s| s.has(anim)),
+        _ => false,
+    }
+}
+
+// テーマの描き方でrectに描く。画像はposeのコマを描く。outlineがあれば周りに枠を描く
+#[allow(clippy::too_many_arguments)]
+fn draw_look(
+    canvas: &mut Canvas<Window>,
+    batch: &mut Batch,
+    layout: &Layout,
+    resources: &mut Resources,
+    look: &Look,
+    color: Color,
+    rect: Rect,
+    pose: Pose,
+    outline: Option<Color>,
+) -> Result<(), String> {
+    if let Some(outline) = outline {
+        // 枠はものと同じように薄くする
+        let outline = Color::RGBA(outline.r, outline.g, outline.b, color.a);
+        match look {
+            Look::Shape(shape) => {
+                canvas.set_draw_color(outline);
+                canvas.draw_lines(shape_outline(*shape, rect).as_slice())?;
+            }
+            _ => batch.frame(outline, rect),
+        }
+    }
+    // 塗るものはbatchにためる
+    let inner = shrink(rect, outline.is_some());
+    let atlas = resources.atlas.as_mut().ok_or("atlas is not built")?;
+    let (src, angle) = match look {
+        Look::Cell | Look::Letters => {
+            batch.fill(color, layout.shape_rows(inner));
+            return Ok(());
+        }
+        Look::Shape(shape) => {
+            batch.fill(color, shape_rows(*shape, inner));
+            return Ok(());
+        }
+        Look::Sprite(file) => (atlas.image(file), pose.angle),
+        Look::Glyph(c) => (atlas.glyph(*c), 0.0),
+    };
+    let src = src.ok_or_else(|| format!("missing image for {:?}", look))?;
+    // 文字は縦長なので縦横比を保ってマスの中央に置く
+    // スプライトシートのある画像はそのコマを、ない画像はコマを並べたものとみなして最初のコマを使う
+    let sheet = match look {
+        Look::Sprite(file) => resources.sheets.get(file),
+        _ => None,
+    };
+    let (src, dest) = if let Look::Glyph(_) = look {
+        let w = (src.width() * rect.height() / src.height().max(1)).min(rect.width());
+        (src, Rect::from_center(rect.center(), w, rect.height()))
+    } else if let Some(sheet) = sheet {
+        let frame = sheet.frame(pose.anim, pose.ms);
+        let (x, y) = (src.x() + frame.x as i32, src.y() + frame.y as i32);
+        (Rect::new(x, y, frame.w, frame.h), rect)
+    } else {
+        let size = src.width().min(src.height());
+        (Rect::new(src.x(), src.y(), size, src.height()), rect)
+    };
+    atlas.texture.set_color_mod(color.r, color.g, color.b);
+    atlas.texture.set_alpha_mod(color.a);
+    canvas.copy_ex(&atlas.texture, src, dest, angle, None, false, false)
+}
+
+// 枠を描くときは中身を1ピクセル小さくして、枠が隠れないようにする
+fn shrink(rect: Rect, outlined: bool) -> Rect {
+    if !outlined {
+        return rect;
+    }
+    Rect::new(
+        rect.x() + 1,
+        rect.y() + 1,
+        rect.width().saturating_sub(2).max(1),
+        rect.height().saturating_sub(2).max(1),
+    )
+}
+
+// 形を1行ずつの横線にする
+fn shape_rows(shape: Shape, rect: Rect) -> Vec<Rect> {
+    let (w, h) = (rect.width() as f64, rect.height() as f64);
+    let span = |y: i32, left: f64, right: f64| {
+        let left = left.round() as i32;
+        let right = (right.round() as i32).max(left + 1);
+        Rect::new(rect.x() + left, rect.y() + y, (right - left) as u32, 1)
+    };
+    let mut rows = Vec::new();
+    for y in 0..rect.height() as i32 {
+        // 行の中心の、上下の真ん中からのずれ(-1.0から1.0)
+        let dy = ((y as f64 + 0.5) - h / 2.0) / (h / 2.0);
+        match shape {
+            Shape::Circle => {
+                let half = w / 2.0 * (1.0 - dy * dy).max(0.0).sqrt();
+                rows.push(span(y, w / 2.0 - half, w / 2.0 + half));
+            }
+            Shape::Diamond => {
+                let half = w / 2.0 * (1.0 - dy.abs());
+                rows.push(span(y, w / 2.0 - half, w / 2.0 + half));
+            }
+            // 上を向いた三角形
+            Shape::Triangle => {
+                let half = w / 2.0 * (y as f64 + 0.5) / h;
+                rows.push(span(y, w / 2.0 - half, w / 2.0 + half));
+            }
+            // 太さ3ピクセルの斜めの線を2本
+            Shape::Cross => {
+                let x = (y as f64 + 0.5) * w / h;
+                rows.push(span(y, x - 1.5, x + 1.5));
+                rows.push(span(y, w - x - 1.5, w - x + 1.5));
+            }
+        }
+    }
+    rows
+}
+
+// 形の周りの折れ線。×印は四角で囲む
+fn shape_outline(shape: Shape, rect: Rect) -> Vec<Point> {
+    let (l, t) = (rect.left(), rect.top());
+    let (r, b) = (rect.right() - 1, rect.bottom() - 1);
+    let (cx, cy) = (rect.center().x(), rect.center().y());
+    match shape {
+        Shape::Circle => (0..=16)
+            .map(|i| {
+                let a = std::f64::consts::TAU * i as f64 / 16.0;
+                Point::new(
+                    cx + (a.cos() * (r - l) as f64 / 2.0).round() as i32,
+                    cy + (a.sin() * (b - t) as f64 / 2.0).round() as i32,
+                )
+            })
+            .collect(),
+        Shape::Diamond => vec![
+            Point::new(cx, t),
+            Point::new(r, cy),
+            Point::new(cx, b),
+            Point::new(l, cy),
+            Point::new(cx, t),
+        ],
+        Shape::Triangle => vec![
+            Point::new(cx, t),
+            Point::new(r, b),
+            Point::new(l, b),
+            Point::new(cx, t),
+        ],
+        Shape::Cross => vec![
+            Point::new(l, t),
+            Point::new(r, t),
+            Point::new(r, b),
+            Point::new(l, b),
+            Point::new(l, t),
+        ],
+    }
+}
+
+// 持っている道具を色の四角で左から並べる
+fn render_inventory(
+    canvas: &mut Canvas<Window>,
+    theme: &Theme,
+    items: &[ItemKind],
+    x: i32,
+) -> Result<(), String> {
+    for (i, kind) in items.iter().take(INVENTORY_MAX).enumerate() {
+        canvas.set_draw_color(color(theme.item(*kind)));
+        canvas.fill_rect(Rect::new(x + i as i32 * 12, INFO_HEIGHT / 2 - 5, 10, 10))?;
+    }
+    Ok(())
+}
+
+// 画面に収まるように縮める。全文は標準出力に出ている
+fn script_status(error: &str) -> String {
+    shorten(&format!("SCRIPT ERROR: {}", error).to_uppercase())
+}
+
+fn warning_status(warning: &str) -> String {
+    shorten(&format!("WARNING: {}", warning).to_uppercase())
+}
+
+fn shorten(text: &str) -> String {
+    if text.chars().count() <= STATUS_MAX_CHARS {
+        return text.to_string();
+    }
+    text.chars().take(STATUS_MAX_CHARS - 3).collect::<String>() + "..."
+}
+
+fn render_toasts(
+    canvas: &mut Canvas<Window>,
+    texts: &mut TextCache,
+    toasts: &[Toast],
+    layout: &Layout,
+    theme: &Theme,
+) -> Result<(), String> {
+    let height = 64;
+    for (i, toast) in toasts.iter().enumerate() {
+        // 最後の1秒でフェードアウトする
+        let alpha = (255 * toast.frames.min(FPS) / FPS) as u8;
+        let top = INFO_HEIGHT + 8 + i as i32 * (height + 8);
+        let (c, title, text) = (theme.toast, theme.toast_title, theme.toast_text);
+        canvas.set_draw_color(Color::RGBA(c.0, c.1, c.2, alpha / 4 * 3));
+        canvas.fill_rect(Rect::new(
+            16,
+            top,
+            (layout.width - 32) as u32,
+            height as u32,
+        ))?;
+        render_font(
+            canvas,
+            texts,
+            toast.title.clone(),
+            layout.width / 2,
+            top + 4,
+            Color::RGBA(title.0, title.1, title.2, alpha),
+            true,
+        );
+        render_font(
+            canvas,
+            texts,
+            toast.description.clone(),
+            layout.width / 2,
+            top + 32,
+            Color::RGBA(text.0, text.1, text.2, alpha),
+            true,
+        );
+    }
+    Ok(())
+}
+
+// クリア時・ゲームオーバー時に今のレベルの統計を表示する
+fn render_summary(
+    canvas: &mut Canvas<Window>,
+    texts: &mut TextCache,
+    game: &Game,
+    layout: &Layout,
+    font_color: Color,
+) -> Result<(), String> {
+    let mut lines = game.stats.summary_lines();
+    lines.push(format!("SCORE {}  TOTAL TURNS {}", game.score, game.turn));
+    if game.players.len() > 1 {
+        lines.push(game.player_scores());
+    }
+    let line_height = 28;
+    let top = layout.height / 2 + 60;
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 192));
+    canvas.fill_rect(Rect::new(
+        16,
+        top - 8,
+        (layout.width - 32) as u32,
+        (lines.len() as i32 * line_height + 16) as u32,
+    ))?;
+    for (i, line) in lines.into_iter().enumerate() {
+        render_font(
+            canvas,
+            texts,
+            line,
+            layout.width / 2,
+            top + i as i32 * line_height,
+            font_color,
+            true,
+        );
+    }
     Ok(())
 }
 
 fn render_font(
     canvas: &mut Canvas<Window>,
-    font: &sdl2::ttf::Font,
+    texts: &mut TextCache,
     text: String,
     x: i32,
     y: i32,
     color: Color,
     center: bool,
 ) {
-    let texture_creator = canvas.texture_creator();
-
-    let surface = font.render(&text).blended(color).unwrap();
-    let texture = texture_creator
-        .create_texture_from_surface(&surface)
-        .unwrap();
+    // 空の文字列はテクスチャにできない
+    if text.is_empty() {
+        return;
+    }
+    let texture = texts.get(&text, color).unwrap();
     let x: i32 = if center {
         x - texture.query().width as i32 / 2
     } else {
@@ -403,7 +2199,7 @@ fn render_font(
     };
     canvas
         .copy(
-            &texture,
+            texture,
             None,
             Rect::new(x, y, texture.query().width, texture.query().height),
         )
@@ -1,4 +1,9 @@
+use crate::script::{Script, ScriptAction};
+use crate::solver::{self, SolverConfig};
+use crate::topology::{Topology, TopologyKind};
 use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::rc::Rc;
 use std::{ops, time};
 
 pub const FPS: i32 = 30;
@@ -9,6 +14,14 @@ pub const CELL_H: i32 = 16;
 pub const ROBOT_COUNT_BASE: i32 = 11;
 pub const ROBOT_COUNT_PER_LEVEL: i32 = 5;
 pub const ROBOT_COUNT_MAX: i32 = FIELD_W * FIELD_H / 4;
+pub const ITEM_COUNT: i32 = 3;
+pub const FREEZE_TURNS: i32 = 5;
+// 砲台を乗っ取っていられるターン数と、撃ってから次に撃てるまでのターン数
+pub const CANNON_CONTROL_TURNS: i32 = 10;
+pub const CANNON_COOLDOWN: i32 = 3;
+// リアルタイムモードでロボットが動く間隔の下限と、レーザー砲が撃つ間隔
+pub const REALTIME_MIN_INTERVAL_MS: i32 = 100;
+pub const REALTIME_LASER_INTERVAL_MS: i32 = 4000;
 
 // $varの値が
 //   > 0 : ウェイト中
@@ -26,7 +39,7 @@ macro_rules! wait {
 }
 pub(crate) use wait;
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub enum Command {
     None,
     Left,
@@ -39,10 +52,94 @@ pub enum Command {
     DownRight,
     Teleport,
     Wait,
+    UseItem(ItemKind),
+    // 隣にあるレーザー砲を乗っ取る。乗っ取っている間は向きを変えて好きなときに撃てる
+    TakeCannon,
+    RotateCannon,
+    FireCannon,
+    // リアルタイムモードで時間が来たときに入れるコマンド。履歴に残るのでリプレイできる
+    RobotTick,
+    LaserTick,
     NextLevel,
 }
 
-#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+impl Command {
+    // 盤面上の操作を1文字で表す(解の表示やリプレイ、--headless、ボット、通信対戦で使う)
+    // 砲台はc r f。ウィンドウのキーはcとrとfが2人目のキーなので、i o p(2人目はr g v)にしている
+    pub fn from_char(c: char) -> Option<Command> {
+        match c {
+            'h' => Some(Command::Left),
+            'l' => Some(Command::Right),
+            'j' => Some(Command::Down),
+            'k' => Some(Command::Up),
+            'y' => Some(Command::UpLeft),
+            'u' => Some(Command::UpRight),
+            'b' => Some(Command::DownLeft),
+            'n' => Some(Command::DownRight),
+            't' => Some(Command::Teleport),
+            '.' => Some(Command::Wait),
+            '1' => Some(Command::UseItem(ItemKind::Emp)),
+            '2' => Some(Command::UseItem(ItemKind::JunkDropper)),
+            '3' => Some(Command::UseItem(ItemKind::Freeze)),
+            '4' => Some(Command::UseItem(ItemKind::CannonControl)),
+            'c' => Some(Command::TakeCannon),
+            'r' => Some(Command::RotateCannon),
+            'f' => Some(Command::FireCannon),
+            '+' => Some(Command::RobotTick),
+            '*' => Some(Command::LaserTick),
+            '>' => Some(Command::NextLevel),
+            _ => None,
+        }
+    }
+
+    pub fn direction(self) -> Option<Direction> {
+        match self {
+            Command::Left => Some(Direction::Left),
+            Command::Right => Some(Direction::Right),
+            Command::Down => Some(Direction::Down),
+            Command::Up => Some(Direction::Up),
+            Command::UpLeft => Some(Direction::UpLeft),
+            Command::UpRight => Some(Direction::UpRight),
+            Command::DownLeft => Some(Direction::DownLeft),
+            Command::DownRight => Some(Direction::DownRight),
+            _ => None,
+        }
+    }
+
+    pub fn is_tick(self) -> bool {
+        matches!(self, Command::RobotTick | Command::LaserTick)
+    }
+
+    pub fn to_char(self) -> Option<char> {
+        match self {
+            Command::Left => Some('h'),
+            Command::Right => Some('l'),
+            Command::Down => Some('j'),
+            Command::Up => Some('k'),
+            Command::UpLeft => Some('y'),
+            Command::UpRight => Some('u'),
+            Command::DownLeft => Some('b'),
+            Command::DownRight => Some('n'),
+            Command::Teleport => Some('t'),
+            Command::Wait => Some('.'),
+            Command::UseItem(ItemKind::Emp) => Some('1'),
+            Command::UseItem(ItemKind::JunkDropper) => Some('2'),
+            Command::UseItem(ItemKind::Freeze) => Some('3'),
+            Command::UseItem(ItemKind::CannonControl) => Some('4'),
+            // シールドは持っているだけで効くので使うコマンドはない
+            Command::UseItem(ItemKind::Shield) => None,
+            Command::TakeCannon => Some('c'),
+            Command::RotateCannon => Some('r'),
+            Command::FireCannon => Some('f'),
+            Command::RobotTick => Some('+'),
+            Command::LaserTick => Some('*'),
+            Command::NextLevel => Some('>'),
+            Command::None => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct Vec2 {
     pub x: i32,
     pub y: i32,
@@ -59,11 +156,23 @@ impl ops::Add<Vec2> for Vec2 {
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+impl ops::Sub<Vec2> for Vec2 {
+    type Output = Vec2;
+
+    fn sub(self, _rhs: Vec2) -> Vec2 {
+        Vec2 {
+            x: self.x - _rhs.x,
+            y: self.y - _rhs.y,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Direction {
     Left,
     Right,
     Down,
+    #[default]
     Up,
     UpLeft,
     UpRight,
@@ -71,8 +180,20 @@ pub enum Direction {
     DownRight,
 }
 
+// 砲台を回す順番
+const CLOCKWISE: [Direction; 8] = [
+    Direction::Up,
+    Direction::UpRight,
+    Direction::Right,
+    Direction::DownRight,
+    Direction::Down,
+    Direction::DownLeft,
+    Direction::Left,
+    Direction::UpLeft,
+];
+
 impl Direction {
-    pub fn to_vec2(&self) -> Vec2 {
+    pub fn to_vec2(self) -> Vec2 {
         match self {
             Direction::Left => Vec2 { x: -1, y: 0 },
             Direction::Right => Vec2 { x: 1, y: 0 },
@@ -84,78 +205,399 @@ impl Direction {
             Direction::DownRight => Vec2 { x: 1, y: 1 },
         }
     }
+
+    pub fn opposite(self) -> Direction {
+        match self {
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+            Direction::Down => Direction::Up,
+            Direction::Up => Direction::Down,
+            Direction::UpLeft => Direction::DownRight,
+            Direction::UpRight => Direction::DownLeft,
+            Direction::DownLeft => Direction::UpRight,
+            Direction::DownRight => Direction::UpLeft,
+        }
+    }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Player {
     pub pos: Vec2,
+    // 直前のコマンドを処理する前の位置。描画で前の位置から動かして見せるのに使う
+    #[serde(default)]
+    pub prev_pos: Vec2,
+    pub alive: bool,
+    pub score: i32,
+    // 拾った道具。レベルをまたいで持ち越す
+    #[serde(default)]
+    pub items: Vec<ItemKind>,
+    // 最後に動いた向き。ジャンクはこの反対側に置く
+    #[serde(default)]
+    pub facing: Option<Direction>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Robot {
     pub pos: Vec2,
+    #[serde(default)]
+    pub prev_pos: Vec2,
     pub exist: bool,
+    // 追いかけているプレイヤーの番号。壊れたときはこのプレイヤーの得点になる
+    pub target: usize,
+    // スクリプトで動きを決めるロボットの種類。空なら普通に追いかける
+    #[serde(default)]
+    pub kind: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Junk {
     pub pos: Vec2,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum ItemKind {
+    // 隣にいるロボットを全部壊す
+    Emp,
+    // ロボットに1回捕まっても助かる。持っているだけで効く
+    Shield,
+    // 後ろにジャンクを置く
+    JunkDropper,
+    // FREEZE_TURNSターンの間ロボットを止める
+    Freeze,
+    // レーザー砲を1回撃たせる
+    CannonControl,
+}
+
+pub const ITEM_KINDS: [ItemKind; 5] = [
+    ItemKind::Emp,
+    ItemKind::Shield,
+    ItemKind::JunkDropper,
+    ItemKind::Freeze,
+    ItemKind::CannonControl,
+];
+
+impl ItemKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ItemKind::Emp => "EMP",
+            ItemKind::Shield => "SHIELD",
+            ItemKind::JunkDropper => "JUNK",
+            ItemKind::Freeze => "FREEZE",
+            ItemKind::CannonControl => "CANNON",
+        }
+    }
+
+    // ヘッドレスモードなどで道具を表す文字
+    pub fn letter(self) -> char {
+        match self {
+            ItemKind::Emp => 'e',
+            ItemKind::Shield => 's',
+            ItemKind::JunkDropper => 'j',
+            ItemKind::Freeze => 'f',
+            ItemKind::CannonControl => 'c',
+        }
+    }
+}
+
+// 盤面に落ちている道具。プレイヤーが上に乗ると拾う
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Item {
+    pub pos: Vec2,
+    pub kind: ItemKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LaserCannon {
     pub pos: Vec2,
     pub turn: i32,
     pub direction: Direction,
+    // 直前のコマンドを処理する前の向き
+    #[serde(default)]
+    pub prev_direction: Direction,
     pub is_shooting: bool,
+    // レーザーが通る範囲。左右はx、それ以外はyの座標で、トーラスでは盤面の外にはみ出す
+    // 観戦のJSONで使われていたので、beamから計算して残しておく
+    #[serde(default)]
     pub begin: i32,
+    #[serde(default)]
     pub end: i32,
+    // レーザーが通ったマス。撃っていないときは空
+    pub beam: Vec<Vec2>,
+    // 乗っ取っているプレイヤー。乗っ取られている間は勝手に撃たない
+    #[serde(default)]
+    pub controller: Option<usize>,
+    // 乗っ取りが終わるまでのターン数
+    #[serde(default)]
+    pub control_turns: i32,
+    // 次に撃てるまでのターン数
+    #[serde(default)]
+    pub cooldown: i32,
 }
 
-#[derive(Debug)]
+impl LaserCannon {
+    // beamを置き換えて、begin..endを合わせる。beamは砲台に近いマスから並んでいる
+    // 斜めはどちらの盤面でも1マスごとにyが1つ変わるので、yで数える
+    pub fn set_beam(&mut self, beam: Vec<Vec2>) {
+        let length = beam.len() as i32;
+        let (pos, forward) = match self.direction {
+            Direction::Left => (self.pos.x, false),
+            Direction::Right => (self.pos.x, true),
+            Direction::Up | Direction::UpLeft | Direction::UpRight => (self.pos.y, false),
+            Direction::Down | Direction::DownLeft | Direction::DownRight => (self.pos.y, true),
+        };
+        (self.begin, self.end) = if length == 0 {
+            (0, 0)
+        } else if forward {
+            (pos + 1, pos + 1 + length)
+        } else {
+            (pos - length, pos)
+        };
+        self.beam = beam;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum KillCause {
+    Collision,
+    Junk,
+    Laser,
+    // EMPとシールド
+    Item,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum DeathCause {
+    Robot,
+    Laser,
+}
+
+// updateの中で起きたこと。requested_soundsと同じく、使う側が取り出して消す
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum GameEvent {
+    LevelStarted {
+        level: i32,
+    },
+    PlayerMoved {
+        player: usize,
+        from: Vec2,
+        to: Vec2,
+    },
+    MoveBlocked {
+        pos: Vec2,
+    },
+    Teleported {
+        player: usize,
+        from: Vec2,
+        to: Vec2,
+    },
+    Waited,
+    // fromはそのターンに動き始めた位置、playerは得点が入るプレイヤー(ロボットが追っていたプレイヤー)
+    RobotDestroyed {
+        pos: Vec2,
+        cause: KillCause,
+        #[serde(default)]
+        from: Vec2,
+        #[serde(default)]
+        player: usize,
+    },
+    // killedはその1発で壊したロボットの数、beamはレーザーが通ったマス
+    LaserFired {
+        killed: i32,
+        #[serde(default)]
+        beam: Vec<Vec2>,
+    },
+    PlayerDied {
+        player: usize,
+        pos: Vec2,
+        cause: DeathCause,
+    },
+    LevelCleared {
+        stats: LevelStats,
+    },
+    ItemPickedUp {
+        player: usize,
+        kind: ItemKind,
+    },
+    // シールドは捕まったときに使われる
+    ItemUsed {
+        player: usize,
+        kind: ItemKind,
+    },
+    CannonTaken {
+        player: usize,
+    },
+    CannonReleased,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LevelStats {
+    pub level: i32,
+    pub turns: i32,
+    pub moves: i32,
+    pub teleports: i32,
+    pub waits: i32,
+    pub killed_by_collision: i32,
+    pub killed_by_junk: i32,
+    pub killed_by_laser: i32,
+    #[serde(default)]
+    pub killed_by_item: i32,
+    pub blocked_by_junk: i32,
+    #[serde(default)]
+    pub items_used: i32,
+}
+
+impl LevelStats {
+    pub fn killed(&self) -> i32 {
+        self.killed_by_collision + self.killed_by_junk + self.killed_by_laser + self.killed_by_item
+    }
+
+    // クリア画面・ゲームオーバー画面とヘッドレスモードで表示する
+    pub fn summary_lines(&self) -> Vec<String> {
+        vec![
+            format!("LEVEL {}  TURNS {}", self.level, self.turns),
+            format!("TELEPORTS {}  WAITS {}", self.teleports, self.waits),
+            format!(
+                "CRASH {}  JUNK {}  LASER {}  ITEM {}",
+                self.killed_by_collision,
+                self.killed_by_junk,
+                self.killed_by_laser,
+                self.killed_by_item
+            ),
+            format!(
+                "KILLED {}  BLOCKED {}  ITEMS {}",
+                self.killed(),
+                self.blocked_by_junk,
+                self.items_used
+            ),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct GameConfig {
+    // 0より大きければ、その手数以内に解けるまで盤面を作り直す
+    pub solvable_depth: i32,
+    // 同じ盤面を2人で交互に遊ぶ
+    pub two_players: bool,
+    // 上下左右の端がつながっている。プレイヤーもロボットもレーザーも反対側に回り込む
+    pub torus: bool,
+    pub topology: TopologyKind,
+    // 0より大きければ霧のモード。プレイヤーからこの距離までの、さえぎられていないマスだけが見える
+    #[serde(default)]
+    pub vision: i32,
+    // 0より大きければリアルタイムモード。ロボットは入力を待たずに、この間隔(ミリ秒)で動く
+    #[serde(default)]
+    pub realtime: i32,
+}
+
+// 霧のモードでのプレイヤー1人の視界
+#[derive(Debug, Clone, Default)]
+pub struct Vision {
+    // FIELD_W * FIELD_H のマスごとに、今見えているか
+    pub visible: Vec<bool>,
+    // game.robotsと同じ順番で、それぞれのロボットを最後に見た位置
+    pub last_seen: Vec<Option<Vec2>>,
+    // 見えないところで壊れたロボットを最後に見た位置
+    pub lost: Vec<Vec2>,
+    // 砲台は一度見たら場所を覚えている
+    pub cannon_seen: bool,
+}
+
+impl Vision {
+    pub fn is_visible(&self, pos: Vec2) -> bool {
+        in_field(pos) && self.visible[(pos.y * FIELD_W + pos.x) as usize]
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Game {
+    pub config: GameConfig,
+    pub seed: u64,
     pub rng: StdRng,
     pub frame: i32,
+    // 実際に処理されたターン数。frameはCommand::Noneのフレームでも増える
+    pub turn: i32,
     pub requested_sounds: Vec<&'static str>,
+    pub events: Vec<GameEvent>,
     pub is_over: bool,
     pub is_clear: bool,
     pub level: i32,
+    pub score: i32,
+    pub stats: LevelStats,
+    // クリア済みのレベルの統計
+    pub stats_history: Vec<LevelStats>,
+    // updateに渡されて実際に処理されたコマンド。seedと合わせればゲームを再現できる
+    pub history: Vec<Command>,
     pub initial_robot_count: i32,
-    pub player: Player,
+    pub players: Vec<Player>,
+    // 次にコマンドを受け付けるプレイヤー
+    pub current_player: usize,
     pub robots: Vec<Robot>,
     pub junks: Vec<Junk>,
+    pub items: Vec<Item>,
+    // ロボットが止まっている残りのターン数
+    pub freeze: i32,
     pub laser_cannon: LaserCannon,
+    // prev_posを今の位置にそろえるたびに増える。描画側はこれが変わったら動きを最初から見せる
+    pub motion: u64,
+    // プレイヤーごとの視界。霧のモードでなければ空
+    pub vision: Vec<Vision>,
+    pub script: Option<Rc<Script>>,
+    // 最後に起きたスクリプトのエラー。HUDに表示する
+    pub script_error: Option<String>,
+    // --solvableで解ける盤面を作れなかったときの警告。HUDに表示する
+    pub level_warning: Option<String>,
 }
 
 impl Game {
-    pub fn new() -> Self {
-        let now = time::SystemTime::now();
-        let timestamp = now
-            .duration_since(time::UNIX_EPOCH)
-            .expect("SystemTime before UNIX EPOCH!")
-            .as_secs();
-        let rng = StdRng::seed_from_u64(timestamp);
-        println!("random seed = {}", timestamp);
+    pub fn with_seed(seed: u64, config: GameConfig) -> Self {
+        Game::with_script(seed, config, None)
+    }
+
+    pub fn with_script(seed: u64, config: GameConfig, script: Option<Rc<Script>>) -> Self {
+        let rng = StdRng::seed_from_u64(seed);
 
         let mut game = Game {
-            rng: rng,
+            config,
+            seed,
+            rng,
             frame: -1,
+            turn: 0,
             requested_sounds: Vec::new(),
+            events: Vec::new(),
             is_over: false,
             is_clear: false,
             level: 0,
+            score: 0,
+            stats: LevelStats::default(),
+            stats_history: Vec::new(),
+            history: Vec::new(),
             initial_robot_count: 0,
-            player: Player::default(),
+            players: Vec::new(),
+            current_player: 0,
             robots: Vec::new(),
             junks: Vec::new(),
+            items: Vec::new(),
+            freeze: 0,
             laser_cannon: LaserCannon {
                 pos: Vec2::default(),
                 turn: 0,
                 direction: Direction::Up,
+                prev_direction: Direction::Up,
                 is_shooting: false,
                 begin: 0,
                 end: 0,
+                beam: Vec::new(),
+                controller: None,
+                control_turns: 0,
+                cooldown: 0,
             },
+            motion: 0,
+            vision: Vec::new(),
+            script,
+            script_error: None,
+            level_warning: None,
         };
 
         game.next_level();
@@ -163,19 +605,74 @@ impl Game {
         game
     }
 
+    // seedとコマンド列からゲームを再現する
+    pub fn replay(seed: u64, config: GameConfig, commands: &[Command]) -> Self {
+        let mut game = Game::with_seed(seed, config);
+        for command in commands {
+            game.update(*command);
+        }
+        game
+    }
+
     pub fn next_level(&mut self) {
+        if self.level > 0 {
+            self.stats_history.push(self.stats.clone());
+        }
+        self.level += 1;
+        self.events
+            .push(GameEvent::LevelStarted { level: self.level });
+        self.setup_level();
+        if self.config.solvable_depth > 0 {
+            let config = SolverConfig::new(self.config.solvable_depth);
+            self.level_warning = solver::reroll_until_solvable(self, &config).err();
+        }
+    }
+
+    // 現在のレベルのまま盤面を作り直す
+    pub fn setup_level(&mut self) {
         self.is_over = false;
         self.is_clear = false;
-        self.level += 1;
-        self.player.pos.x = FIELD_W / 2;
-        self.player.pos.y = FIELD_H / 2;
+        self.stats = LevelStats {
+            level: self.level,
+            ..Default::default()
+        };
+        let player_count = if self.config.two_players { 2 } else { 1 };
+        self.players.resize(player_count, Player::default());
+        for (i, player) in self.players.iter_mut().enumerate() {
+            // 2人のときは中央の左右に並べる
+            player.pos.x = FIELD_W / 2 + (i as i32 * 4 - (player_count as i32 - 1) * 2);
+            player.pos.y = FIELD_H / 2;
+            player.alive = true;
+        }
+        self.current_player = 0;
         self.robots = Vec::new();
         self.junks = Vec::new();
+        self.freeze = 0;
         self.spawn_robots();
         self.set_laser_cannon();
+        self.spawn_items();
+        self.begin_motion();
+        // 前のレベルで見たものは忘れる
+        self.vision.clear();
+        self.update_visibility();
     }
 
     pub fn spawn_robots(&mut self) {
+        if let Some(Some(robots)) = self.run_script(|script, game| script.spawn(game)) {
+            self.initial_robot_count = robots.len() as i32;
+            self.robots = robots
+                .into_iter()
+                .map(|(pos, kind)| Robot {
+                    pos,
+                    prev_pos: pos,
+                    exist: true,
+                    target: 0,
+                    kind,
+                })
+                .collect();
+            self.apply_script_actions();
+            return;
+        }
         let robot_count = clamp(
             0,
             ROBOT_COUNT_BASE + self.level * ROBOT_COUNT_PER_LEVEL,
@@ -186,8 +683,10 @@ impl Game {
             let x = self.rng.gen_range(0..FIELD_W);
             let y = self.rng.gen_range(0..FIELD_H);
             let mut should_add = true;
-            if x.abs_diff(self.player.pos.x) <= 1 && y.abs_diff(self.player.pos.y) <= 1 {
-                should_add = false;
+            for player in &self.players {
+                if x.abs_diff(player.pos.x) <= 1 && y.abs_diff(player.pos.y) <= 1 {
+                    should_add = false;
+                }
             }
             for robot in &self.robots {
                 if robot.pos.x == x && robot.pos.y == y {
@@ -198,12 +697,34 @@ impl Game {
             if should_add {
                 self.robots.push(Robot {
                     pos: Vec2 { x, y },
+                    prev_pos: Vec2 { x, y },
                     exist: true,
+                    target: 0,
+                    kind: String::new(),
                 })
             }
         }
     }
 
+    pub fn spawn_items(&mut self) {
+        self.items = Vec::new();
+        while (self.items.len() as i32) < ITEM_COUNT {
+            let pos = Vec2 {
+                x: self.rng.gen_range(0..FIELD_W),
+                y: self.rng.gen_range(0..FIELD_H),
+            };
+            let kind = ITEM_KINDS[self.rng.gen_range(0..ITEM_KINDS.len())];
+            if self.is_player(pos.x, pos.y)
+                || self.robots.iter().any(|r| r.pos == pos)
+                || self.items.iter().any(|i| i.pos == pos)
+                || self.laser_cannon.pos == pos
+            {
+                continue;
+            }
+            self.items.push(Item { pos, kind });
+        }
+    }
+
     pub fn set_laser_cannon(&mut self) {
         let quarter_w = FIELD_W / 4;
         let quarter_h = FIELD_H / 4;
@@ -238,7 +759,7 @@ impl Game {
             _ => panic!(),
         };
         self.laser_cannon = LaserCannon {
-            pos: pos,
+            pos,
             turn: 0,
             direction: match corner {
                 0 => {
@@ -277,10 +798,18 @@ impl Game {
                     panic!();
                 }
             },
+            prev_direction: Direction::Up,
             is_shooting: false,
             begin: 0,
             end: 0,
+            beam: Vec::new(),
+            controller: None,
+            control_turns: 0,
+            cooldown: 0,
         };
+        self.laser_cannon.direction = self
+            .topology()
+            .cannon_direction(self.laser_cannon.direction, corner % 2 == 0);
     }
 
     pub fn update(&mut self, command: Command) {
@@ -292,27 +821,119 @@ impl Game {
 
         if self.is_clear {
             if command == Command::NextLevel {
+                self.history.push(command);
                 self.next_level();
             }
             return;
         }
 
+        // ターン制のときは時計で進めない
+        if command.is_tick() && self.config.realtime <= 0 {
+            return;
+        }
+
+        if command != Command::None && command != Command::NextLevel {
+            self.history.push(command);
+            self.begin_motion();
+        }
+
+        // 前のターンのレーザーはそのターンの最初のコマンドで消す
+        // リアルタイムモードでは次にロボットが動くまで残す
+        let turn_start = if self.config.realtime > 0 {
+            command == Command::RobotTick
+        } else {
+            !self.players[..self.current_player].iter().any(|p| p.alive)
+        };
+        if turn_start {
+            self.laser_cannon.is_shooting = false;
+            self.laser_cannon.set_beam(Vec::new());
+        }
+
+        // コマンドはcurrent_playerのもの
         match command {
             Command::None => return,
-            Command::Left => self.move_player(Direction::Left),
-            Command::Right => self.move_player(Direction::Right),
-            Command::Down => self.move_player(Direction::Down),
-            Command::Up => self.move_player(Direction::Up),
-            Command::UpLeft => self.move_player(Direction::UpLeft),
-            Command::UpRight => self.move_player(Direction::UpRight),
-            Command::DownLeft => self.move_player(Direction::DownLeft),
-            Command::DownRight => self.move_player(Direction::DownRight),
-            Command::Teleport => self.teleport(),
-            Command::Wait => {}
+            Command::Left
+            | Command::Right
+            | Command::Down
+            | Command::Up
+            | Command::UpLeft
+            | Command::UpRight
+            | Command::DownLeft
+            | Command::DownRight => self.move_player(command.direction().unwrap()),
+            Command::Teleport => {
+                self.teleport();
+                self.stats.teleports += 1;
+            }
+            Command::Wait => {
+                self.stats.waits += 1;
+                self.events.push(GameEvent::Waited);
+            }
+            Command::UseItem(kind) => self.use_item(kind),
+            Command::TakeCannon => self.take_cannon(),
+            Command::RotateCannon => self.rotate_cannon(),
+            Command::FireCannon => self.fire_cannon(),
+            Command::RobotTick => {
+                self.advance_turn();
+                return;
+            }
+            Command::LaserTick => {
+                if self.laser_cannon.controller.is_none() {
+                    self.fire_laser();
+                }
+                self.check_clear();
+                self.remove_destroyed_robots();
+                self.update_visibility();
+                return;
+            }
             Command::NextLevel => return,
         }
+        // 道具やレーザーで壊れたロボットがこのターンに動かないように取り除いておく
+        self.remove_destroyed_robots();
+        self.pick_up_item();
+
+        // リアルタイムモードではプレイヤーの動きをすぐに反映して、ロボットはRobotTickを待つ
+        if self.config.realtime > 0 {
+            self.check_gameover();
+            self.check_clear();
+            self.remove_destroyed_robots();
+            self.update_visibility();
+            return;
+        }
+
+        // 生きているプレイヤー全員が動いてから、ロボットが動く
+        if self.advance_player() {
+            self.update_visibility();
+            return;
+        }
+
+        self.advance_turn();
+    }
+
+    // 今の位置と向きを動く前のものとして覚える
+    fn begin_motion(&mut self) {
+        for player in &mut self.players {
+            player.prev_pos = player.pos;
+        }
+        for robot in &mut self.robots {
+            robot.prev_pos = robot.pos;
+        }
+        self.laser_cannon.prev_direction = self.laser_cannon.direction;
+        self.motion += 1;
+    }
 
-        self.move_robots();
+    // プレイヤーが動いた後にロボットと砲台を進める
+    fn advance_turn(&mut self) {
+        self.turn += 1;
+        self.stats.turns += 1;
+
+        if self.freeze > 0 {
+            self.freeze -= 1;
+        } else {
+            self.move_robots();
+        }
+
+        self.run_script(|script, game| script.on_turn(game));
+        self.apply_script_actions();
 
         self.update_laser_cannon();
 
@@ -323,21 +944,288 @@ impl Game {
 
         self.check_clear();
 
+        self.remove_destroyed_robots();
+
+        self.current_player = self.players.iter().position(|p| p.alive).unwrap_or(0);
+
+        self.update_visibility();
+    }
+
+    // 次にまだこのターン動いていない生きているプレイヤーがいれば、その番にしてtrueを返す
+    fn advance_player(&mut self) -> bool {
+        let next = (self.current_player + 1..self.players.len()).find(|i| self.players[*i].alive);
+        match next {
+            Some(i) => {
+                self.current_player = i;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // 一番近い生きているプレイヤー
+    pub fn nearest_player(&self, pos: Vec2) -> Option<usize> {
+        (0..self.players.len())
+            .filter(|i| self.players[*i].alive)
+            .min_by_key(|i| self.distance(pos, self.players[*i].pos))
+    }
+
+    pub fn topology(&self) -> &'static dyn Topology {
+        self.config.topology.get()
+    }
+
+    // トーラスのときは、端を回り込んだ先にあるtoの写しのうちfromに一番近いもの
+    pub fn nearest_copy(&self, from: Vec2, to: Vec2) -> Vec2 {
+        if !self.config.torus {
+            return to;
+        }
+        let mut copies = Vec::new();
+        for dy in [0, -FIELD_H, FIELD_H] {
+            for dx in [0, -FIELD_W, FIELD_W] {
+                copies.push(Vec2 {
+                    x: to.x + dx,
+                    y: to.y + dy,
+                });
+            }
+        }
+        copies
+            .into_iter()
+            .min_by_key(|p| self.topology().distance(from, *p))
+            .unwrap()
+    }
+
+    // fromからtoまで何手か
+    pub fn distance(&self, from: Vec2, to: Vec2) -> i32 {
+        self.topology().distance(from, self.nearest_copy(from, to))
+    }
+
+    // トーラスのときは盤面の外の位置を反対側に回り込ませる
+    pub fn wrap(&self, pos: Vec2) -> Vec2 {
+        if !self.config.torus {
+            return pos;
+        }
+        Vec2 {
+            x: pos.x.rem_euclid(FIELD_W),
+            y: pos.y.rem_euclid(FIELD_H),
+        }
+    }
+
+    // プレイヤーごとの視界を計算し直す。死んだプレイヤーには何も見えない
+    // 距離がvision以内で、間のマスがジャンクや砲台でさえぎられていないマスが見える
+    // 盤面の外(壁)は見えず、トーラスのときは端の向こうが見える
+    pub fn update_visibility(&mut self) {
+        if self.config.vision <= 0 {
+            self.vision.clear();
+            return;
+        }
+        self.vision.resize(self.players.len(), Vision::default());
+        for p in 0..self.players.len() {
+            let mut visible = vec![false; (FIELD_W * FIELD_H) as usize];
+            if self.players[p].alive {
+                let from = self.players[p].pos;
+                for y in 0..FIELD_H {
+                    for x in 0..FIELD_W {
+                        let to = self.nearest_copy(from, Vec2 { x, y });
+                        if self.topology().distance(from, to) > self.config.vision {
+                            continue;
+                        }
+                        let line = self.topology().line(from, to);
+                        // 両端はさえぎるものにならない
+                        let blocked = line.len() > 2
+                            && line[1..line.len() - 1].iter().any(|cell| {
+                                let cell = self.wrap(*cell);
+                                self.is_junk(cell.x, cell.y) || cell == self.laser_cannon.pos
+                            });
+                        visible[(y * FIELD_W + x) as usize] = !blocked;
+                    }
+                }
+            }
+            let previous = std::mem::take(&mut self.vision[p]);
+            let mut vision = Vision {
+                visible,
+                ..Default::default()
+            };
+            // 最後に見た位置が見えていてそこにいなければ、どこに行ったかわからない
+            vision.last_seen = self
+                .robots
+                .iter()
+                .enumerate()
+                .map(|(i, robot)| {
+                    if vision.is_visible(robot.pos) {
+                        return Some(robot.pos);
+                    }
+                    let seen = previous.last_seen.get(i).copied().flatten();
+                    seen.filter(|pos| !vision.is_visible(*pos))
+                })
+                .collect();
+            vision.lost = previous
+                .lost
+                .into_iter()
+                .filter(|pos| !vision.is_visible(*pos))
+                .collect();
+            vision.cannon_seen = previous.cannon_seen || vision.is_visible(self.laser_cannon.pos);
+            self.vision[p] = vision;
+        }
+    }
+
+    // viewerのプレイヤーにposが見えているか。Noneなら誰か1人にでも見えているか
+    // 霧のモードでないときや観戦中は全部見える
+    pub fn is_visible(&self, viewer: Option<usize>, pos: Vec2) -> bool {
+        self.vision.is_empty() || self.visions(viewer).any(|v| v.is_visible(pos))
+    }
+
+    pub fn is_cannon_seen(&self, viewer: Option<usize>) -> bool {
+        self.vision.is_empty() || self.visions(viewer).any(|v| v.cannon_seen)
+    }
+
+    // 今は見えていないが、ロボットを最後に見た位置
+    pub fn remembered_robots(&self, viewer: Option<usize>) -> Vec<Vec2> {
+        let mut result: Vec<Vec2> = Vec::new();
+        for vision in self.visions(viewer) {
+            for pos in vision.last_seen.iter().flatten().chain(&vision.lost) {
+                if !self.is_visible(viewer, *pos) && !result.contains(pos) {
+                    result.push(*pos);
+                }
+            }
+        }
+        result
+    }
+
+    // 壊れたロボットを取り除く。視界の記憶もロボットの順番に合わせて詰める
+    fn remove_destroyed_robots(&mut self) {
+        for vision in &mut self.vision {
+            let mut last_seen = Vec::new();
+            for (robot, seen) in self.robots.iter().zip(&vision.last_seen) {
+                match (robot.exist, seen) {
+                    (true, _) => last_seen.push(*seen),
+                    (false, Some(pos)) => vision.lost.push(*pos),
+                    (false, None) => {}
+                }
+            }
+            vision.last_seen = last_seen;
+        }
         self.robots.retain(|x| x.exist);
     }
 
+    fn visions(&self, viewer: Option<usize>) -> impl Iterator<Item = &Vision> {
+        self.vision
+            .iter()
+            .enumerate()
+            .filter(move |(i, _)| viewer.is_none_or(|v| v == *i))
+            .map(|(_, v)| v)
+    }
+
+    // 通信対戦でお互いの状態がずれていないか確かめるためのハッシュ
+    // 相手が別のRustや別のOSでビルドしたものでも同じ値になるよう、決まった順にi64にしてFNV-1aにかける
+    pub fn state_hash(&self) -> u64 {
+        let mut hash = Fnv1a::new();
+        hash.write(self.level as i64);
+        hash.write(self.turn as i64);
+        hash.write(self.score as i64);
+        hash.write(self.is_over as i64);
+        hash.write(self.is_clear as i64);
+        hash.write(self.current_player as i64);
+        hash.write(self.players.len() as i64);
+        for player in &self.players {
+            hash.write_pos(player.pos);
+            hash.write(player.alive as i64);
+            hash.write(player.score as i64);
+            // ジャンクを落とす位置はこの向きで決まる。向いていないときは-1
+            hash.write(player.facing.map_or(-1, |d| d as i64));
+            hash.write(player.items.len() as i64);
+            for item in &player.items {
+                hash.write(item.letter() as i64);
+            }
+        }
+        hash.write(self.robots.len() as i64);
+        for robot in &self.robots {
+            hash.write_pos(robot.pos);
+            hash.write(robot.target as i64);
+            hash.write_str(&robot.kind);
+        }
+        hash.write(self.junks.len() as i64);
+        for junk in &self.junks {
+            hash.write_pos(junk.pos);
+        }
+        hash.write(self.items.len() as i64);
+        for item in &self.items {
+            hash.write_pos(item.pos);
+            hash.write(item.kind.letter() as i64);
+        }
+        hash.write(self.freeze as i64);
+        hash.write_pos(self.laser_cannon.pos);
+        hash.write(self.laser_cannon.turn as i64);
+        hash.write(self.laser_cannon.direction as i64);
+        // 乗っ取られていないときは-1
+        hash.write(self.laser_cannon.controller.map_or(-1, |c| c as i64));
+        hash.write(self.laser_cannon.control_turns as i64);
+        hash.write(self.laser_cannon.cooldown as i64);
+        hash.finish()
+    }
+
+    // 残りのロボットの数の表示。霧のモードでは見えない所で壊れたのがわかってしまうので'?'にする
+    pub fn robots_left(&self) -> String {
+        if self.vision.is_empty() {
+            format!("{}/{}", self.robots.len(), self.initial_robot_count)
+        } else {
+            format!("?/{}", self.initial_robot_count)
+        }
+    }
+
+    // 2人のときの得点表示
+    pub fn player_scores(&self) -> String {
+        self.players
+            .iter()
+            .enumerate()
+            .map(|(i, p)| format!("P{} {}", i + 1, p.score))
+            .collect::<Vec<_>>()
+            .join("  ")
+    }
+
+    pub fn is_player(&self, x: i32, y: i32) -> bool {
+        self.players
+            .iter()
+            .any(|p| p.alive && p.pos.x == x && p.pos.y == y)
+    }
+
     pub fn move_player(&mut self, direction: Direction) {
-        let v = direction.to_vec2();
-        let x = self.player.pos.x + v.x;
-        let y = self.player.pos.y + v.y;
-        if 0 <= x && x < FIELD_W && 0 <= y && y < FIELD_H {
-            if self.is_junk(x, y) || (self.laser_cannon.pos.x == x && self.laser_cannon.pos.y == y)
+        let current = self.current_player;
+        // 六角形の盤面にはUpとDownがない
+        if !self.topology().directions().contains(&direction) {
+            self.requested_sounds.push("ng.wav");
+            self.events.push(GameEvent::MoveBlocked {
+                pos: self.players[current].pos,
+            });
+            return;
+        }
+        let Vec2 { x, y } = self.wrap(
+            self.topology()
+                .neighbor(self.players[current].pos, direction),
+        );
+        if (0..FIELD_W).contains(&x) && (0..FIELD_H).contains(&y) {
+            if self.is_junk(x, y)
+                || (self.laser_cannon.pos.x == x && self.laser_cannon.pos.y == y)
+                || self.is_player(x, y)
             {
+                if self.is_junk(x, y) {
+                    self.stats.blocked_by_junk += 1;
+                }
                 self.requested_sounds.push("ng.wav");
+                self.events
+                    .push(GameEvent::MoveBlocked { pos: Vec2 { x, y } });
                 return;
             }
-            self.player.pos.x = x;
-            self.player.pos.y = y;
+            let from = self.players[current].pos;
+            self.players[current].pos.x = x;
+            self.players[current].pos.y = y;
+            self.players[current].facing = Some(direction);
+            // 動けなかったときは数えない(STATUEの実績に響く)
+            self.stats.moves += 1;
+            self.events.push(GameEvent::PlayerMoved {
+                player: current,
+                from,
+                to: self.players[current].pos,
+            });
         }
     }
 
@@ -346,81 +1234,301 @@ impl Game {
     }
 
     pub fn teleport(&mut self) {
+        let current = self.current_player;
         let x = self.rng.gen_range(0..FIELD_W);
         let y = self.rng.gen_range(0..FIELD_H);
-        self.player.pos.x = x;
-        self.player.pos.y = y;
+        let from = self.players[current].pos;
+        self.players[current].pos.x = x;
+        self.players[current].pos.y = y;
         self.requested_sounds.push("shoot.wav");
+        self.events.push(GameEvent::Teleported {
+            player: current,
+            from,
+            to: self.players[current].pos,
+        });
     }
 
     pub fn move_robots(&mut self) {
-        for robot in &mut self.robots {
-            let vx: i32 = (self.player.pos.x - robot.pos.x).signum();
-            let vy: i32 = (self.player.pos.y - robot.pos.y).signum();
-            robot.pos.x = clamp(0, robot.pos.x + vx, FIELD_W - 1);
-            robot.pos.y = clamp(0, robot.pos.y + vy, FIELD_H - 1);
+        for i in 0..self.robots.len() {
+            let Some(target) = self.nearest_player(self.robots[i].pos) else {
+                return;
+            };
+            self.robots[i].target = target;
+            if !self.robots[i].kind.is_empty() {
+                if let Some(Some(pos)) = self.run_script(|script, game| script.move_robot(game, i))
+                {
+                    self.robots[i].pos = pos;
+                    continue;
+                }
+            }
+            let from = self.robots[i].pos;
+            let goal = self.nearest_copy(from, self.players[target].pos);
+            let next = self.wrap(self.topology().step_toward(from, goal));
+            let robot = &mut self.robots[i];
+            robot.pos.x = clamp(0, next.x, FIELD_W - 1);
+            robot.pos.y = clamp(0, next.y, FIELD_H - 1);
         }
     }
 
-    pub fn update_laser_cannon(&mut self) {
-        self.laser_cannon.turn += 1;
-        if self.laser_cannon.turn % 8 == 0 {
-            self.laser_cannon.is_shooting = true;
-            self.requested_sounds.push("laser.wav");
-            match self.laser_cannon.direction {
-                Direction::Left => {
-                    self.laser_cannon.begin = 0;
-                    self.laser_cannon.end = self.laser_cannon.pos.x;
-                }
-                Direction::Right => {
-                    self.laser_cannon.begin = self.laser_cannon.pos.x + 1;
-                    self.laser_cannon.end = FIELD_W;
-                }
-                Direction::Up => {
-                    self.laser_cannon.begin = 0;
-                    self.laser_cannon.end = self.laser_cannon.pos.y;
+    // スクリプトの関数を呼ぶ。エラーのときはHUDに出すために覚えておき、Noneを返して組み込みの動きに任せる
+    fn run_script<T>(&mut self, f: impl FnOnce(&Script, &Game) -> Result<T, String>) -> Option<T> {
+        let script = self.script.clone()?;
+        match f(&script, self) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                if self.script_error.as_ref() != Some(&e) {
+                    println!("script error: {}", e);
                 }
-                Direction::Down => {
-                    self.laser_cannon.begin = self.laser_cannon.pos.y + 1;
-                    self.laser_cannon.end = FIELD_H;
+                self.script_error = Some(e);
+                None
+            }
+        }
+    }
+
+    fn apply_script_actions(&mut self) {
+        let Some(script) = self.script.clone() else {
+            return;
+        };
+        for action in script.take_actions() {
+            match action {
+                ScriptAction::AddJunk(pos) if in_field(pos) => self.junks.push(Junk { pos }),
+                ScriptAction::AddRobot(pos, kind) if in_field(pos) => self.robots.push(Robot {
+                    pos,
+                    prev_pos: pos,
+                    exist: true,
+                    target: 0,
+                    kind,
+                }),
+                action => {
+                    self.script_error = Some(format!("{:?} is outside the field", action));
                 }
-                _ => panic!(),
-            };
-            if self.laser_cannon.direction == Direction::Left
-                || self.laser_cannon.direction == Direction::Right
-            {
-                for x in self.laser_cannon.begin..self.laser_cannon.end {
-                    if self.player.pos.x == x && self.player.pos.y == self.laser_cannon.pos.y {
-                        self.is_over = true;
-                        self.requested_sounds.push("crash.wav");
-                    }
-                    for i in 0..self.robots.len() {
-                        if self.robots[i].pos.x == x
-                            && self.robots[i].pos.y == self.laser_cannon.pos.y
-                        {
-                            self.robots[i].exist = false;
-                            self.requested_sounds.push("hit.wav");
-                        }
-                    }
+            }
+        }
+    }
+
+    pub fn update_laser_cannon(&mut self) {
+        self.laser_cannon.turn += 1;
+        if self.laser_cannon.cooldown > 0 {
+            self.laser_cannon.cooldown -= 1;
+        }
+        if let Some(player) = self.laser_cannon.controller {
+            self.laser_cannon.control_turns -= 1;
+            if self.laser_cannon.control_turns <= 0 || !self.players[player].alive {
+                self.laser_cannon.controller = None;
+                self.laser_cannon.control_turns = 0;
+                self.events.push(GameEvent::CannonReleased);
+            }
+            return;
+        }
+        // リアルタイムモードではLaserTickで撃つ
+        if self.laser_cannon.turn % 8 == 0 && self.config.realtime <= 0 {
+            self.fire_laser();
+        }
+    }
+
+    // リアルタイムモードでロボットが動く間隔。レベルが上がるごとに1割ずつ速くなる
+    pub fn robot_interval_ms(&self) -> i32 {
+        let interval = self.config.realtime as f64 * 0.9f64.powi(self.level - 1);
+        (interval as i32).max(REALTIME_MIN_INTERVAL_MS)
+    }
+
+    pub fn can_take_cannon(&self) -> bool {
+        let player = &self.players[self.current_player];
+        self.laser_cannon.controller.is_none()
+            && self.distance(player.pos, self.laser_cannon.pos) == 1
+    }
+
+    pub fn controls_cannon(&self) -> bool {
+        self.laser_cannon.controller == Some(self.current_player)
+    }
+
+    // current_playerがそのコマンドを今使えるか。使えないコマンドも1ターン待ったことになるので、画面ではその前に止める
+    pub fn is_available(&self, command: Command) -> bool {
+        match command {
+            Command::UseItem(ItemKind::Shield) => false,
+            Command::UseItem(kind) => self.has_item(kind),
+            Command::TakeCannon => self.can_take_cannon(),
+            Command::RotateCannon => self.controls_cannon(),
+            Command::FireCannon => self.controls_cannon() && self.laser_cannon.cooldown == 0,
+            _ => true,
+        }
+    }
+
+    fn take_cannon(&mut self) {
+        if !self.can_take_cannon() {
+            self.requested_sounds.push("ng.wav");
+            return;
+        }
+        self.laser_cannon.controller = Some(self.current_player);
+        self.laser_cannon.control_turns = CANNON_CONTROL_TURNS;
+        self.requested_sounds.push("pickup.wav");
+        self.events.push(GameEvent::CannonTaken {
+            player: self.current_player,
+        });
+    }
+
+    // 盤面で撃てる向きだけを時計回りに回る
+    fn rotate_cannon(&mut self) {
+        if !self.controls_cannon() {
+            self.requested_sounds.push("ng.wav");
+            return;
+        }
+        let directions = self.topology().directions();
+        let order: Vec<Direction> = CLOCKWISE
+            .iter()
+            .copied()
+            .filter(|d| directions.contains(d))
+            .collect();
+        let index = order
+            .iter()
+            .position(|d| *d == self.laser_cannon.direction)
+            .unwrap_or(0);
+        self.laser_cannon.direction = order[(index + 1) % order.len()];
+    }
+
+    fn fire_cannon(&mut self) {
+        if !self.is_available(Command::FireCannon) {
+            self.requested_sounds.push("ng.wav");
+            return;
+        }
+        self.fire_laser();
+        self.laser_cannon.cooldown = CANNON_COOLDOWN;
+    }
+
+    // 砲台の向きにレーザーを撃って、当たったプレイヤーとロボットを壊す
+    // 最初のコードは縦に撃つときプレイヤーのxを砲台のyと比べていたので、縦のレーザーは
+    // プレイヤーにほとんど当たらなかった。今はロボットと同じく通ったマスと比べるので当たる
+    pub fn fire_laser(&mut self) {
+        let killed_before = self.stats.killed_by_laser;
+        self.laser_cannon.is_shooting = true;
+        self.requested_sounds.push("laser.wav");
+        self.laser_cannon.set_beam(self.laser_beam());
+        for cell in self.laser_cannon.beam.clone() {
+            for p in 0..self.players.len() {
+                if self.players[p].pos == cell {
+                    self.kill_player(p, DeathCause::Laser);
                 }
-            } else {
-                for y in self.laser_cannon.begin..self.laser_cannon.end {
-                    if self.player.pos.x == self.laser_cannon.pos.y && self.player.pos.y == y {
-                        self.is_over = true;
-                        self.requested_sounds.push("crash.wav");
-                    }
-                    for i in 0..self.robots.len() {
-                        if self.robots[i].pos.x == self.laser_cannon.pos.x
-                            && self.robots[i].pos.y == y
-                        {
-                            self.robots[i].exist = false;
-                            self.requested_sounds.push("hit.wav");
-                        }
-                    }
+            }
+            for i in 0..self.robots.len() {
+                if self.robots[i].exist && self.robots[i].pos == cell {
+                    self.destroy_robot(i, KillCause::Laser);
+                    self.requested_sounds.push("hit.wav");
                 }
             }
-        } else {
-            self.laser_cannon.is_shooting = false;
+        }
+        self.events.push(GameEvent::LaserFired {
+            killed: self.stats.killed_by_laser - killed_before,
+            beam: self.laser_cannon.beam.clone(),
+        });
+    }
+
+    pub fn has_item(&self, kind: ItemKind) -> bool {
+        self.players[self.current_player].items.contains(&kind)
+    }
+
+    fn pick_up_item(&mut self) {
+        let current = self.current_player;
+        let pos = self.players[current].pos;
+        let Some(index) = self.items.iter().position(|item| item.pos == pos) else {
+            return;
+        };
+        let kind = self.items.remove(index).kind;
+        self.players[current].items.push(kind);
+        self.requested_sounds.push("pickup.wav");
+        self.events.push(GameEvent::ItemPickedUp {
+            player: current,
+            kind,
+        });
+    }
+
+    // current_playerの道具を使う。持っていないときや使えないときは移動できなかったときと同じく1ターン待つ
+    pub fn use_item(&mut self, kind: ItemKind) {
+        let current = self.current_player;
+        let Some(index) = self.players[current].items.iter().position(|k| *k == kind) else {
+            self.requested_sounds.push("ng.wav");
+            return;
+        };
+        let used = match kind {
+            ItemKind::Emp => {
+                self.emp();
+                true
+            }
+            ItemKind::Shield => false,
+            ItemKind::JunkDropper => self.drop_junk(),
+            ItemKind::Freeze => {
+                self.freeze = FREEZE_TURNS;
+                true
+            }
+            ItemKind::CannonControl => {
+                self.fire_laser();
+                true
+            }
+        };
+        if !used {
+            self.requested_sounds.push("ng.wav");
+            return;
+        }
+        self.players[current].items.remove(index);
+        self.stats.items_used += 1;
+        self.events.push(GameEvent::ItemUsed {
+            player: current,
+            kind,
+        });
+    }
+
+    fn emp(&mut self) {
+        let pos = self.players[self.current_player].pos;
+        let neighbors: Vec<Vec2> = self
+            .topology()
+            .directions()
+            .iter()
+            .map(|d| self.wrap(self.topology().neighbor(pos, *d)))
+            .collect();
+        self.requested_sounds.push("shoot.wav");
+        for i in 0..self.robots.len() {
+            if self.robots[i].exist && neighbors.contains(&self.robots[i].pos) {
+                self.destroy_robot(i, KillCause::Item);
+                self.requested_sounds.push("hit.wav");
+            }
+        }
+    }
+
+    // 最後に動いた向きの反対側にジャンクを置く。そこにいるロボットは壊れる
+    fn drop_junk(&mut self) -> bool {
+        let player = &self.players[self.current_player];
+        let Some(facing) = player.facing else {
+            return false;
+        };
+        let pos = self.wrap(self.topology().neighbor(player.pos, facing.opposite()));
+        if !in_field(pos)
+            || self.is_junk(pos.x, pos.y)
+            || self.is_player(pos.x, pos.y)
+            || self.laser_cannon.pos == pos
+        {
+            return false;
+        }
+        self.junks.push(Junk { pos });
+        for i in 0..self.robots.len() {
+            if self.robots[i].exist && self.robots[i].pos == pos {
+                self.destroy_robot(i, KillCause::Junk);
+                self.requested_sounds.push("hit.wav");
+            }
+        }
+        true
+    }
+
+    // 砲台から向きの方向にたどって、盤面の外に出るまでのマス
+    // トーラスのときは砲台に戻ってくるまで
+    fn laser_beam(&self) -> Vec<Vec2> {
+        let cannon = &self.laser_cannon;
+        let mut beam = Vec::new();
+        let mut pos = cannon.pos;
+        loop {
+            pos = self.wrap(self.topology().neighbor(pos, cannon.direction));
+            if !in_field(pos) || pos == cannon.pos || beam.len() as i32 >= FIELD_W * FIELD_H {
+                return beam;
+            }
+            beam.push(pos);
         }
     }
 
@@ -428,7 +1536,7 @@ impl Game {
         for i in 0..self.robots.len() {
             if self.robots[i].exist {
                 if self.is_junk(self.robots[i].pos.x, self.robots[i].pos.y) {
-                    self.robots[i].exist = false;
+                    self.destroy_robot(i, KillCause::Junk);
                     self.requested_sounds.push("hit.wav");
                 }
                 for j in (i + 1)..self.robots.len() {
@@ -436,8 +1544,8 @@ impl Game {
                         self.junks.push(Junk {
                             pos: self.robots[i].pos,
                         });
-                        self.robots[i].exist = false;
-                        self.robots[j].exist = false;
+                        self.destroy_robot(i, KillCause::Collision);
+                        self.destroy_robot(j, KillCause::Collision);
                         self.requested_sounds.push("hit.wav");
                     }
                 }
@@ -445,16 +1553,75 @@ impl Game {
         }
     }
 
+    pub fn destroy_robot(&mut self, i: usize, cause: KillCause) {
+        if self.robots[i].exist {
+            self.robots[i].exist = false;
+            self.score += 1;
+            if let Some(player) = self.players.get_mut(self.robots[i].target) {
+                player.score += 1;
+            }
+            self.events.push(GameEvent::RobotDestroyed {
+                pos: self.robots[i].pos,
+                cause,
+                from: self.robots[i].prev_pos,
+                player: self.robots[i].target,
+            });
+            match cause {
+                KillCause::Collision => self.stats.killed_by_collision += 1,
+                KillCause::Junk => self.stats.killed_by_junk += 1,
+                KillCause::Laser => self.stats.killed_by_laser += 1,
+                KillCause::Item => self.stats.killed_by_item += 1,
+            }
+        }
+    }
+
     pub fn check_gameover(&mut self) {
-        for robot in &self.robots {
-            if robot.pos == self.player.pos {
-                self.is_over = true;
-                self.requested_sounds.push("crash.wav");
-                break;
+        for p in 0..self.players.len() {
+            if !self.robots.iter().any(|r| r.pos == self.players[p].pos) {
+                continue;
+            }
+            // シールドを持っていれば、捕まえたロボットを壊して助かる
+            let shield = self.players[p]
+                .items
+                .iter()
+                .position(|k| *k == ItemKind::Shield);
+            match shield {
+                Some(index) if self.players[p].alive => {
+                    self.players[p].items.remove(index);
+                    self.stats.items_used += 1;
+                    self.events.push(GameEvent::ItemUsed {
+                        player: p,
+                        kind: ItemKind::Shield,
+                    });
+                    for i in 0..self.robots.len() {
+                        if self.robots[i].pos == self.players[p].pos {
+                            self.destroy_robot(i, KillCause::Item);
+                        }
+                    }
+                    self.requested_sounds.push("hit.wav");
+                }
+                _ => self.kill_player(p, DeathCause::Robot),
             }
         }
     }
 
+    // 生きているプレイヤーがいなくなったらゲームオーバー
+    pub fn kill_player(&mut self, index: usize, cause: DeathCause) {
+        if !self.players[index].alive {
+            return;
+        }
+        self.players[index].alive = false;
+        self.requested_sounds.push("crash.wav");
+        self.events.push(GameEvent::PlayerDied {
+            player: index,
+            pos: self.players[index].pos,
+            cause,
+        });
+        if self.players.iter().all(|p| !p.alive) {
+            self.is_over = true;
+        }
+    }
+
     pub fn check_clear(&mut self) {
         if !self.is_over
             && self
@@ -466,10 +1633,24 @@ impl Game {
         {
             self.is_clear = true;
             self.requested_sounds.push("bravo.wav");
+            self.events.push(GameEvent::LevelCleared {
+                stats: self.stats.clone(),
+            });
         }
     }
 }
 
+pub fn timestamp_seed() -> u64 {
+    let now = time::SystemTime::now();
+    now.duration_since(time::UNIX_EPOCH)
+        .expect("SystemTime before UNIX EPOCH!")
+        .as_secs()
+}
+
+fn in_field(pos: Vec2) -> bool {
+    (0..FIELD_W).contains(&pos.x) && (0..FIELD_H).contains(&pos.y)
+}
+
 fn clamp<T: PartialOrd>(min: T, value: T, max: T) -> T {
     if value < min {
         return min;
@@ -479,3 +1660,115 @@ fn clamp<T: PartialOrd>(min: T, value: T, max: T) -> T {
     }
     value
 }
+
+// 64ビットのFNV-1a。state_hashで使う
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    // 数はリトルエンディアンの8バイトとして混ぜる
+    fn write(&mut self, n: i64) {
+        for byte in n.to_le_bytes() {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_pos(&mut self, pos: Vec2) {
+        self.write(pos.x as i64);
+        self.write(pos.y as i64);
+    }
+
+    // 長さを先に混ぜて、続く文字列と区別できるようにする
+    fn write_str(&mut self, text: &str) {
+        self.write(text.len() as i64);
+        for byte in text.bytes() {
+            self.write(byte as i64);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(x: i32, y: i32) -> Vec2 {
+        Vec2 { x, y }
+    }
+
+    fn torus() -> Game {
+        Game::with_seed(
+            1,
+            GameConfig {
+                torus: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn nearest_copy_wraps_around_the_edges() {
+        let game = torus();
+        assert_eq!(game.nearest_copy(v(0, 0), v(FIELD_W - 1, 0)), v(-1, 0));
+        assert_eq!(
+            game.nearest_copy(v(FIELD_W - 1, FIELD_H - 1), v(0, 0)),
+            v(FIELD_W, FIELD_H)
+        );
+        assert_eq!(game.nearest_copy(v(10, 10), v(12, 9)), v(12, 9));
+        assert_eq!(game.distance(v(0, 0), v(FIELD_W - 1, FIELD_H - 1)), 1);
+    }
+
+    #[test]
+    fn nearest_copy_without_torus() {
+        let game = Game::with_seed(1, GameConfig::default());
+        assert_eq!(
+            game.nearest_copy(v(0, 0), v(FIELD_W - 1, 0)),
+            v(FIELD_W - 1, 0)
+        );
+        assert_eq!(game.distance(v(0, 0), v(FIELD_W - 1, 0)), FIELD_W - 1);
+    }
+
+    #[test]
+    fn state_hash_follows_the_state() {
+        let a = Game::with_seed(3, GameConfig::default());
+        let mut b = Game::with_seed(3, GameConfig::default());
+        assert_eq!(a.state_hash(), b.state_hash());
+        b.freeze = 1;
+        assert_ne!(a.state_hash(), b.state_hash());
+        // JunkDropperや得点の食い違いも見つける
+        let changes: [fn(&mut Game); 3] = [
+            |g| g.players[0].facing = Some(Direction::Left),
+            |g| g.players[0].score += 1,
+            |g| g.robots[0].kind = "zigzag".to_string(),
+        ];
+        for change in changes {
+            let mut b = a.clone();
+            change(&mut b);
+            assert_ne!(a.state_hash(), b.state_hash());
+        }
+    }
+
+    #[test]
+    fn blocked_moves_are_not_counted() {
+        let mut game = Game::with_seed(1, GameConfig::default());
+        let pos = game.players[0].pos;
+        let target = game.topology().neighbor(pos, Direction::Left);
+        game.robots.retain(|r| r.pos != target);
+        game.junks = vec![Junk { pos: target }];
+        game.move_player(Direction::Left);
+        assert_eq!(game.players[0].pos, pos);
+        assert_eq!(game.stats.moves, 0);
+        assert_eq!(game.stats.blocked_by_junk, 1);
+        game.junks.clear();
+        game.move_player(Direction::Left);
+        assert_eq!(game.players[0].pos, target);
+        assert_eq!(game.stats.moves, 1);
+    }
+}
@@ -0,0 +1,393 @@
+// 通信対戦
+//
+// 1行1メッセージのテキストプロトコル
+//   host -> join : "ROBOTS <version> <seed> <race|shared> <solvable_depth> <flat|torus> <square|hex> <vision>"
+//   join -> host : "OK"
+//   お互いに     : "C <command char> <state hash>"  コマンドを実行した直後に送る
+//                  "BYE"                             終了するとき
+//
+// 受け取ったコマンドを同じように実行して、ハッシュが一致しなければずれ(desync)とみなす
+// sharedで相手の番でないのにコマンドが届いたら切る
+// race   : 同じseedでそれぞれ自分の盤面を遊ぶ。相手の盤面は手元で再現して進み具合を表示する
+// shared : 1つの盤面を2人で交互に遊ぶ(hostがプレイヤー1、joinがプレイヤー2)
+
+use crate::model::*;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+// 5: state_hashをFNV-1aにした
+// 6: state_hashにプレイヤーの得点と向き、ロボットの追う相手と種類を入れた
+pub const PROTOCOL_VERSION: i32 = 6;
+
+// 送信はメインループから行うので、相手が読まなくなっても長くは止まらないようにする
+// この時間がたっても書き込めなければ切る
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum VersusMode {
+    #[default]
+    Race,
+    Shared,
+}
+
+impl VersusMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            VersusMode::Race => "race",
+            VersusMode::Shared => "shared",
+        }
+    }
+}
+
+impl std::str::FromStr for VersusMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "race" => Ok(VersusMode::Race),
+            "shared" => Ok(VersusMode::Shared),
+            _ => Err(format!("unknown versus mode: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetStatus {
+    Connected,
+    Disconnected(String),
+    Desync { turn: i32 },
+}
+
+pub struct Session {
+    pub mode: VersusMode,
+    pub seed: u64,
+    pub config: GameConfig,
+    // shared のときの自分のプレイヤー番号
+    pub local_player: usize,
+    pub status: NetStatus,
+    // race のときの相手の盤面
+    pub opponent: Option<Game>,
+    stream: TcpStream,
+    receiver: Receiver<String>,
+}
+
+impl Session {
+    pub fn host(
+        port: u16,
+        seed: u64,
+        mode: VersusMode,
+        config: GameConfig,
+    ) -> Result<Self, String> {
+        let listener = TcpListener::bind(("0.0.0.0", port)).map_err(|e| e.to_string())?;
+        println!("waiting for the opponent on port {}", port);
+        let (mut stream, addr) = listener.accept().map_err(|e| e.to_string())?;
+        println!("connected from {}", addr);
+
+        let config = session_config(mode, config);
+        writeln!(stream, "{}", hello(seed, mode, &config)).map_err(|e| e.to_string())?;
+
+        let mut reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+        let reply = read_line(&mut reader)?;
+        if reply != "OK" {
+            return Err(format!("unexpected handshake reply: {}", reply));
+        }
+        Session::start(stream, reader, mode, seed, config, 0)
+    }
+
+    pub fn join(addr: &str) -> Result<Self, String> {
+        let mut stream = TcpStream::connect(addr).map_err(|e| e.to_string())?;
+        let mut reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+        let (seed, mode, config) = parse_hello(&read_line(&mut reader)?)?;
+        writeln!(stream, "OK").map_err(|e| e.to_string())?;
+        println!(
+            "connected to {} ({} mode, seed {})",
+            addr,
+            mode.name(),
+            seed
+        );
+        Session::start(stream, reader, mode, seed, config, 1)
+    }
+
+    fn start(
+        stream: TcpStream,
+        mut reader: BufReader<TcpStream>,
+        mode: VersusMode,
+        seed: u64,
+        config: GameConfig,
+        local_player: usize,
+    ) -> Result<Self, String> {
+        stream
+            .set_write_timeout(Some(WRITE_TIMEOUT))
+            .map_err(|e| e.to_string())?;
+        // 受信は別スレッドで行い、メインループはチャンネルをのぞくだけにする
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || loop {
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if sender.send(line.trim().to_string()).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(Session {
+            mode,
+            seed,
+            config,
+            local_player,
+            status: NetStatus::Connected,
+            opponent: match mode {
+                VersusMode::Race => Some(Game::with_seed(seed, config)),
+                VersusMode::Shared => None,
+            },
+            stream,
+            receiver,
+        })
+    }
+
+    pub fn new_game(&self) -> Game {
+        Game::with_seed(self.seed, self.config)
+    }
+
+    // 自分のコマンドを実行した後に呼ぶ
+    pub fn send_command(&mut self, command: Command, game: &Game) {
+        let Some(c) = command.to_char() else {
+            return;
+        };
+        self.send(&format!("C {} {}", c, game.state_hash()));
+    }
+
+    // 届いたコマンドを実行する。sharedなら自分の盤面、raceなら相手の盤面
+    pub fn poll(&mut self, game: &mut Game) {
+        loop {
+            let line = match self.receiver.try_recv() {
+                Ok(line) => line,
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    self.disconnect("connection closed");
+                    return;
+                }
+            };
+            if line == "BYE" {
+                self.disconnect("opponent left");
+                return;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let ["C", c, hash] = fields.as_slice() else {
+                self.disconnect(&format!("unexpected message: {}", line));
+                return;
+            };
+            let command = c.chars().next().and_then(Command::from_char);
+            let (Some(command), Ok(hash)) = (command, hash.parse::<u64>()) else {
+                self.disconnect(&format!("unexpected message: {}", line));
+                return;
+            };
+            let target = match self.opponent.as_mut() {
+                Some(opponent) => {
+                    opponent.update(command);
+                    opponent.requested_sounds.clear();
+                    opponent.events.clear();
+                    opponent
+                }
+                None => {
+                    if !self.is_peer_turn(command, game) {
+                        self.disconnect(&format!("command out of turn: {}", line));
+                        return;
+                    }
+                    game.update(command);
+                    &mut *game
+                }
+            };
+            if target.state_hash() != hash && self.status == NetStatus::Connected {
+                println!("desync detected at turn {}", target.turn);
+                self.status = NetStatus::Desync { turn: target.turn };
+            }
+        }
+    }
+
+    // sharedで相手がcommandを送ってよいか。次のレベルに進めるのはhostだけ
+    fn is_peer_turn(&self, command: Command, game: &Game) -> bool {
+        match command {
+            Command::NextLevel => self.local_player != 0,
+            _ => game.current_player != self.local_player,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        !matches!(self.status, NetStatus::Disconnected(_))
+    }
+
+    pub fn close(&mut self) {
+        self.send("BYE");
+    }
+
+    // 情報欄に出す1行
+    pub fn status_line(&self) -> String {
+        match &self.status {
+            NetStatus::Disconnected(reason) => format!("DISCONNECTED: {}", reason.to_uppercase()),
+            NetStatus::Desync { turn } => format!("DESYNC AT TURN {}", turn),
+            NetStatus::Connected => match &self.opponent {
+                Some(opponent) => format!(
+                    "OPPONENT LV {}  SCORE {}{}",
+                    opponent.level,
+                    opponent.score,
+                    if opponent.is_over { "  OVER" } else { "" }
+                ),
+                None => format!("YOU ARE P{}", self.local_player + 1),
+            },
+        }
+    }
+
+    fn send(&mut self, message: &str) {
+        if !self.is_connected() {
+            return;
+        }
+        if let Err(e) = writeln!(self.stream, "{}", message) {
+            self.disconnect(&e.to_string());
+        }
+    }
+
+    fn disconnect(&mut self, reason: &str) {
+        if self.is_connected() {
+            println!("disconnected: {}", reason);
+            self.status = NetStatus::Disconnected(reason.to_string());
+        }
+    }
+}
+
+// hostが最初に送る行
+fn hello(seed: u64, mode: VersusMode, config: &GameConfig) -> String {
+    format!(
+        "ROBOTS {} {} {} {} {} {} {}",
+        PROTOCOL_VERSION,
+        seed,
+        mode.name(),
+        config.solvable_depth,
+        if config.torus { "torus" } else { "flat" },
+        config.topology.name(),
+        config.vision
+    )
+}
+
+// hostから届いた最初の行を読んで、seedとモードと盤面の設定を返す
+fn parse_hello(hello: &str) -> Result<(u64, VersusMode, GameConfig), String> {
+    let fields: Vec<&str> = hello.split_whitespace().collect();
+    let ["ROBOTS", version, seed, mode, solvable_depth, shape, topology, vision] =
+        fields.as_slice()
+    else {
+        return Err(format!("unexpected handshake: {}", hello));
+    };
+    if version.parse() != Ok(PROTOCOL_VERSION) {
+        return Err(format!("protocol version mismatch: {}", version));
+    }
+    let seed: u64 = seed
+        .parse()
+        .map_err(|_| format!("invalid seed: {}", seed))?;
+    let mode: VersusMode = mode.parse()?;
+    let solvable_depth: i32 = solvable_depth
+        .parse()
+        .map_err(|_| format!("invalid solvable_depth: {}", solvable_depth))?;
+    let torus = match *shape {
+        "flat" => false,
+        "torus" => true,
+        _ => return Err(format!("invalid shape: {}", shape)),
+    };
+    let vision: i32 = vision
+        .parse()
+        .map_err(|_| format!("invalid vision: {}", vision))?;
+    let config = session_config(
+        mode,
+        GameConfig {
+            solvable_depth,
+            torus,
+            topology: topology.parse()?,
+            vision,
+            ..Default::default()
+        },
+    );
+    Ok((seed, mode, config))
+}
+
+fn session_config(mode: VersusMode, config: GameConfig) -> GameConfig {
+    GameConfig {
+        two_players: mode == VersusMode::Shared,
+        ..config
+    }
+}
+
+fn read_line(reader: &mut BufReader<TcpStream>) -> Result<String, String> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(0) => Err("connection closed during handshake".to_string()),
+        Ok(_) => Ok(line.trim().to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::topology::TopologyKind;
+
+    #[test]
+    fn handshake_round_trip() {
+        let config = session_config(
+            VersusMode::Shared,
+            GameConfig {
+                solvable_depth: 12,
+                torus: true,
+                topology: TopologyKind::Hex,
+                vision: 6,
+                ..Default::default()
+            },
+        );
+        let (seed, mode, received) = parse_hello(&hello(42, VersusMode::Shared, &config)).unwrap();
+        assert_eq!(seed, 42);
+        assert_eq!(mode, VersusMode::Shared);
+        assert_eq!(received.solvable_depth, 12);
+        assert!(received.torus);
+        assert_eq!(received.topology, TopologyKind::Hex);
+        assert_eq!(received.vision, 6);
+        assert!(received.two_players);
+    }
+
+    #[test]
+    fn handshake_race_is_one_player() {
+        let config = session_config(VersusMode::Race, GameConfig::default());
+        let (_, mode, received) = parse_hello(&hello(7, VersusMode::Race, &config)).unwrap();
+        assert_eq!(mode, VersusMode::Race);
+        assert!(!received.two_players);
+        assert!(!received.torus);
+        assert_eq!(received.topology, TopologyKind::Square);
+    }
+
+    #[test]
+    fn handshake_rejects_other_versions_and_garbage() {
+        let hello = format!("ROBOTS {} 1 race 0 flat square 0", PROTOCOL_VERSION - 1);
+        assert!(parse_hello(&hello)
+            .unwrap_err()
+            .starts_with("protocol version mismatch"));
+        assert!(parse_hello("HELLO")
+            .unwrap_err()
+            .starts_with("unexpected handshake"));
+        let hello = format!("ROBOTS {} 1 coop 0 flat square 0", PROTOCOL_VERSION);
+        assert_eq!(
+            parse_hello(&hello).unwrap_err(),
+            "unknown versus mode: coop"
+        );
+        let hello = format!("ROBOTS {} 1 race x flat square 0", PROTOCOL_VERSION);
+        assert_eq!(
+            parse_hello(&hello).unwrap_err(),
+            "invalid solvable_depth: x"
+        );
+        let hello = format!("ROBOTS {} 1 race 0 flat square -", PROTOCOL_VERSION);
+        assert_eq!(parse_hello(&hello).unwrap_err(), "invalid vision: -");
+        let hello = format!("ROBOTS {} 1 race 0 round square 0", PROTOCOL_VERSION);
+        assert_eq!(parse_hello(&hello).unwrap_err(), "invalid shape: round");
+    }
+}
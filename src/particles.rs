@@ -0,0 +1,181 @@
+// パーティクルと画面の揺れ
+//
+// モデルのイベントから作る
+//   ロボット同士の衝突 : 火花
+//   ロボットがジャンクに当たった : 破片
+//   レーザー : 通ったマスの焦げ
+//   プレイヤーの死 : 画面の揺れと破片
+// 位置はマスとそのマスの中心からのずれ(ピクセル)で持つので、六角形の盤面やトーラスの写しにもそのまま描ける
+// 多すぎると重いのでPARTICLE_MAX個までにして、あふれたら古いものから消す
+
+use crate::model::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+pub const PARTICLE_MAX: usize = 1500;
+pub const SHAKE_FRAMES: i32 = 15;
+pub const SHAKE_PIXELS: f64 = 6.0;
+// 破片が落ちる速さ
+const GRAVITY: f64 = 0.25;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ParticleKind {
+    Spark,
+    Debris,
+    Scorch,
+}
+
+pub struct Particle {
+    pub cell: Vec2,
+    pub dx: f64,
+    pub dy: f64,
+    vx: f64,
+    vy: f64,
+    // 動き出すまでのフレーム数。アニメーションで動き終わってから出す
+    delay: i32,
+    life: i32,
+    max_life: i32,
+    pub kind: ParticleKind,
+}
+
+impl Particle {
+    pub fn is_waiting(&self) -> bool {
+        self.delay > 0
+    }
+
+    // 残りの寿命の割合
+    pub fn fade(&self) -> f64 {
+        self.life as f64 / self.max_life as f64
+    }
+}
+
+pub struct Particles {
+    pub enabled: bool,
+    pub particles: Vec<Particle>,
+    shake: i32,
+    rng: StdRng,
+}
+
+impl Particles {
+    pub fn new(enabled: bool) -> Self {
+        Particles {
+            enabled,
+            particles: Vec::new(),
+            shake: 0,
+            rng: StdRng::seed_from_u64(timestamp_seed()),
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.particles.clear();
+        self.shake = 0;
+    }
+
+    // delayはアニメーションが終わるまでのフレーム数
+    pub fn handle_event(&mut self, event: &GameEvent, delay: i32) {
+        if !self.enabled {
+            return;
+        }
+        match event {
+            GameEvent::RobotDestroyed {
+                pos,
+                cause: KillCause::Collision,
+                ..
+            } => self.emit(ParticleKind::Spark, *pos, 16, 4.0, 12, delay),
+            GameEvent::RobotDestroyed {
+                pos,
+                cause: KillCause::Junk,
+                ..
+            } => self.emit(ParticleKind::Debris, *pos, 10, 2.5, 20, delay),
+            // 焦げ跡は撃ったときのレーザーの位置に付ける。同じフレームで次のターンに進むと、砲台のbeamは変わっている
+            GameEvent::LaserFired { beam, .. } => {
+                for cell in beam {
+                    self.emit(ParticleKind::Scorch, *cell, 2, 0.3, 45, delay);
+                }
+            }
+            GameEvent::PlayerDied { pos, .. } => {
+                self.emit(ParticleKind::Debris, *pos, 24, 3.5, 30, delay);
+                self.shake = SHAKE_FRAMES + delay;
+            }
+            _ => {}
+        }
+        if self.particles.len() > PARTICLE_MAX {
+            let excess = self.particles.len() - PARTICLE_MAX;
+            self.particles.drain(..excess);
+        }
+    }
+
+    fn emit(
+        &mut self,
+        kind: ParticleKind,
+        cell: Vec2,
+        count: i32,
+        speed: f64,
+        life: i32,
+        delay: i32,
+    ) {
+        let half = CELL_W as f64 / 2.0;
+        for _ in 0..count {
+            let angle = self.rng.gen_range(0.0..std::f64::consts::TAU);
+            let speed = speed * self.rng.gen_range(0.3..1.0);
+            let life = self.rng.gen_range(life / 2..=life);
+            self.particles.push(Particle {
+                cell,
+                // 焦げはマスの中にばらまく
+                dx: if kind == ParticleKind::Scorch {
+                    self.rng.gen_range(-half..half)
+                } else {
+                    0.0
+                },
+                dy: if kind == ParticleKind::Scorch {
+                    self.rng.gen_range(-half..half)
+                } else {
+                    0.0
+                },
+                vx: angle.cos() * speed,
+                vy: angle.sin() * speed,
+                delay,
+                life,
+                max_life: life,
+                kind,
+            });
+        }
+    }
+
+    pub fn advance(&mut self) {
+        for p in &mut self.particles {
+            if p.delay > 0 {
+                p.delay -= 1;
+                continue;
+            }
+            p.dx += p.vx;
+            p.dy += p.vy;
+            match p.kind {
+                ParticleKind::Spark => {
+                    p.vx *= 0.85;
+                    p.vy *= 0.85;
+                }
+                ParticleKind::Debris => p.vy += GRAVITY,
+                ParticleKind::Scorch => p.vy -= 0.02,
+            }
+            p.life -= 1;
+        }
+        self.particles.retain(|p| p.life > 0);
+        if self.shake > 0 {
+            self.shake -= 1;
+        }
+    }
+
+    // 画面を揺らす量。揺れが収まるにつれて小さくする
+    pub fn shake_offset(&mut self) -> (i32, i32) {
+        if self.shake <= 0 || self.shake > SHAKE_FRAMES {
+            return (0, 0);
+        }
+        let amount = SHAKE_PIXELS * self.shake as f64 / SHAKE_FRAMES as f64;
+        (
+            self.rng.gen_range(-amount..=amount) as i32,
+            self.rng.gen_range(-amount..=amount) as i32,
+        )
+    }
+}
@@ -0,0 +1,290 @@
+use crate::model::*;
+use crate::storage;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Achievement {
+    Statue,
+    LaserShow,
+    Veteran,
+    Survivor,
+    NoTeleport,
+    Scrapyard,
+}
+
+pub const ACHIEVEMENTS: [Achievement; 6] = [
+    Achievement::Statue,
+    Achievement::LaserShow,
+    Achievement::Veteran,
+    Achievement::Survivor,
+    Achievement::NoTeleport,
+    Achievement::Scrapyard,
+];
+
+// 記録を保存する区切り。レベルをクリアしたときと、プレイヤーがやられたとき
+pub fn is_checkpoint(event: &GameEvent) -> bool {
+    matches!(
+        event,
+        GameEvent::LevelCleared { .. } | GameEvent::PlayerDied { .. }
+    )
+}
+
+impl Achievement {
+    // 保存用のキー
+    pub fn key(self) -> &'static str {
+        match self {
+            Achievement::Statue => "statue",
+            Achievement::LaserShow => "laser_show",
+            Achievement::Veteran => "veteran",
+            Achievement::Survivor => "survivor",
+            Achievement::NoTeleport => "no_teleport",
+            Achievement::Scrapyard => "scrapyard",
+        }
+    }
+
+    pub fn title(self) -> &'static str {
+        match self {
+            Achievement::Statue => "STATUE",
+            Achievement::LaserShow => "LASER SHOW",
+            Achievement::Veteran => "VETERAN",
+            Achievement::Survivor => "SURVIVOR",
+            Achievement::NoTeleport => "GROUNDED",
+            Achievement::Scrapyard => "SCRAPYARD",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Achievement::Statue => "CLEAR A LEVEL WITHOUT MOVING",
+            Achievement::LaserShow => "10 ROBOTS WITH ONE LASER",
+            Achievement::Veteran => "CLEAR LEVEL 10",
+            Achievement::Survivor => "CLEAR LEVEL 5",
+            Achievement::NoTeleport => "3 LEVELS WITHOUT TELEPORT",
+            Achievement::Scrapyard => "DESTROY 1000 ROBOTS",
+        }
+    }
+
+    fn from_key(key: &str) -> Option<Achievement> {
+        ACHIEVEMENTS.iter().copied().find(|a| a.key() == key)
+    }
+}
+
+// 複数のゲームにまたがる累計の記録
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub games: i32,
+    pub levels_cleared: i32,
+    pub killed_by_collision: i32,
+    pub killed_by_junk: i32,
+    pub killed_by_laser: i32,
+    pub killed_by_item: i32,
+    pub best_level: i32,
+    pub longest_no_teleport_streak: i32,
+    pub achievements: Vec<Achievement>,
+    // 今のゲームでテレポートせずに続けてクリアしたレベル数。保存しない
+    no_teleport_streak: i32,
+    // 次のLaserFiredまでに、レーザーで自分のロボットを壊した数。保存しない
+    laser_kills: i32,
+}
+
+impl Profile {
+    fn path() -> PathBuf {
+        storage::data_dir().join("profile.txt")
+    }
+
+    pub fn load() -> Self {
+        let Some(map) = storage::read_kv(&Profile::path()) else {
+            return Profile::default();
+        };
+        let get = |key: &str| storage::get_parsed(&map, key).unwrap_or(0);
+        Profile {
+            games: get("games"),
+            levels_cleared: get("levels_cleared"),
+            killed_by_collision: get("killed_by_collision"),
+            killed_by_junk: get("killed_by_junk"),
+            killed_by_laser: get("killed_by_laser"),
+            killed_by_item: get("killed_by_item"),
+            best_level: get("best_level"),
+            longest_no_teleport_streak: get("longest_no_teleport_streak"),
+            achievements: map
+                .get("achievements")
+                .map(|v| v.split(',').filter_map(Achievement::from_key).collect())
+                .unwrap_or_default(),
+            no_teleport_streak: 0,
+            laser_kills: 0,
+        }
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let mut map = BTreeMap::new();
+        let mut set = |key: &str, value: i32| {
+            map.insert(key.to_string(), value.to_string());
+        };
+        set("games", self.games);
+        set("levels_cleared", self.levels_cleared);
+        set("killed_by_collision", self.killed_by_collision);
+        set("killed_by_junk", self.killed_by_junk);
+        set("killed_by_laser", self.killed_by_laser);
+        set("killed_by_item", self.killed_by_item);
+        set("best_level", self.best_level);
+        set(
+            "longest_no_teleport_streak",
+            self.longest_no_teleport_streak,
+        );
+        map.insert(
+            "achievements".to_string(),
+            self.achievements
+                .iter()
+                .map(|a| a.key())
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+        storage::write_kv(&Profile::path(), &map)
+    }
+
+    pub fn killed(&self) -> i32 {
+        self.killed_by_collision + self.killed_by_junk + self.killed_by_laser + self.killed_by_item
+    }
+
+    pub fn summary_lines(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("games: {}", self.games),
+            format!("levels cleared: {}", self.levels_cleared),
+            format!("best level: {}", self.best_level),
+            format!(
+                "robots destroyed: {} (collision {}, junk {}, laser {}, item {})",
+                self.killed(),
+                self.killed_by_collision,
+                self.killed_by_junk,
+                self.killed_by_laser,
+                self.killed_by_item
+            ),
+            format!(
+                "longest no-teleport streak: {}",
+                self.longest_no_teleport_streak
+            ),
+            "achievements:".to_string(),
+        ];
+        for a in ACHIEVEMENTS {
+            let mark = if self.achievements.contains(&a) {
+                "x"
+            } else {
+                " "
+            };
+            lines.push(format!("  [{}] {} - {}", mark, a.title(), a.description()));
+        }
+        lines
+    }
+
+    // イベントを反映して、新しく解除された実績を返す
+    // playerは通信対戦のsharedでこの端末のプレイヤー。相手に得点が入るロボットは数えない
+    // Noneなら全員を自分として数える
+    pub fn handle_event(&mut self, event: &GameEvent, player: Option<usize>) -> Vec<Achievement> {
+        let mut unlocked = Vec::new();
+        match event {
+            GameEvent::LevelStarted { level } => {
+                if *level == 1 {
+                    self.games += 1;
+                    self.no_teleport_streak = 0;
+                }
+                self.best_level = self.best_level.max(*level);
+            }
+            GameEvent::RobotDestroyed {
+                cause,
+                player: target,
+                ..
+            } if player.is_none_or(|p| p == *target) => {
+                match cause {
+                    KillCause::Collision => self.killed_by_collision += 1,
+                    KillCause::Junk => self.killed_by_junk += 1,
+                    KillCause::Laser => {
+                        self.killed_by_laser += 1;
+                        self.laser_kills += 1;
+                    }
+                    KillCause::Item => self.killed_by_item += 1,
+                }
+                if self.killed() >= 1000 {
+                    unlocked.push(Achievement::Scrapyard);
+                }
+            }
+            // レーザーで壊れたロボットのイベントはLaserFiredより前に並んでいる
+            GameEvent::LaserFired { .. } => {
+                let kills = std::mem::take(&mut self.laser_kills);
+                if kills >= 10 {
+                    unlocked.push(Achievement::LaserShow);
+                }
+            }
+            GameEvent::LevelCleared { stats } => {
+                self.levels_cleared += 1;
+                if stats.teleports == 0 {
+                    self.no_teleport_streak += 1;
+                } else {
+                    self.no_teleport_streak = 0;
+                }
+                self.longest_no_teleport_streak =
+                    self.longest_no_teleport_streak.max(self.no_teleport_streak);
+                if stats.moves == 0 && stats.teleports == 0 {
+                    unlocked.push(Achievement::Statue);
+                }
+                if stats.level >= 5 {
+                    unlocked.push(Achievement::Survivor);
+                }
+                if stats.level >= 10 {
+                    unlocked.push(Achievement::Veteran);
+                }
+                if self.no_teleport_streak >= 3 {
+                    unlocked.push(Achievement::NoTeleport);
+                }
+            }
+            _ => {}
+        }
+        unlocked.retain(|a| !self.achievements.contains(a));
+        self.achievements.extend(&unlocked);
+        unlocked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn destroyed(cause: KillCause, player: usize) -> GameEvent {
+        GameEvent::RobotDestroyed {
+            pos: Vec2 { x: 0, y: 0 },
+            cause,
+            from: Vec2 { x: 0, y: 0 },
+            player,
+        }
+    }
+
+    #[test]
+    fn shared_games_count_only_the_local_player() {
+        let mut profile = Profile::default();
+        profile.handle_event(&destroyed(KillCause::Junk, 0), Some(1));
+        profile.handle_event(&destroyed(KillCause::Junk, 1), Some(1));
+        assert_eq!(profile.killed(), 1);
+        profile.handle_event(&destroyed(KillCause::Junk, 0), None);
+        assert_eq!(profile.killed(), 2);
+    }
+
+    #[test]
+    fn laser_show_counts_only_the_local_player() {
+        let mut profile = Profile::default();
+        let laser = GameEvent::LaserFired {
+            killed: 10,
+            beam: Vec::new(),
+        };
+        for i in 0..10 {
+            profile.handle_event(&destroyed(KillCause::Laser, i % 2), Some(0));
+        }
+        assert!(profile.handle_event(&laser, Some(0)).is_empty());
+        for _ in 0..10 {
+            profile.handle_event(&destroyed(KillCause::Laser, 0), Some(0));
+        }
+        assert_eq!(
+            profile.handle_event(&laser, Some(0)),
+            vec![Achievement::LaserShow]
+        );
+    }
+}
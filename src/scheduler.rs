@@ -0,0 +1,112 @@
+// リアルタイムモードの時計
+//
+// メインループは毎フレームdueを呼び、時刻が来たコマンドをGame::updateに渡す
+// フレームが遅れたときは遅れた分をまとめて返す。ただしSCHEDULER_MAX_CATCH_UP回分まで
+
+use crate::model::Command;
+use std::time::{Duration, Instant};
+
+pub const SCHEDULER_MAX_CATCH_UP: u32 = 5;
+
+struct Timer {
+    command: Command,
+    interval: Duration,
+    next: Instant,
+}
+
+#[derive(Default)]
+pub struct Scheduler {
+    timers: Vec<Timer>,
+}
+
+impl Scheduler {
+    // intervalごとにcommandを出す。もうあるコマンドなら間隔だけ変えて、次の時刻はそのままにする
+    pub fn set(&mut self, command: Command, interval: Duration, now: Instant) {
+        match self.timers.iter_mut().find(|t| t.command == command) {
+            Some(timer) => timer.interval = interval,
+            None => self.timers.push(Timer {
+                command,
+                interval,
+                next: now + interval,
+            }),
+        }
+    }
+
+    // どのコマンドもnowから数え直す
+    pub fn reset(&mut self, now: Instant) {
+        for timer in &mut self.timers {
+            timer.next = now + timer.interval;
+        }
+    }
+
+    // nowまでに時刻が来たコマンドを古い順に返す
+    pub fn due(&mut self, now: Instant) -> Vec<Command> {
+        let mut result: Vec<(Instant, Command)> = Vec::new();
+        for timer in &mut self.timers {
+            if now > timer.next + timer.interval * SCHEDULER_MAX_CATCH_UP {
+                timer.next = now - timer.interval * SCHEDULER_MAX_CATCH_UP;
+            }
+            while timer.next <= now {
+                result.push((timer.next, timer.command));
+                timer.next += timer.interval;
+            }
+        }
+        result.sort_by_key(|(at, _)| *at);
+        result.into_iter().map(|(_, command)| command).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn due_returns_commands_in_time_order() {
+        let start = Instant::now();
+        let mut scheduler = Scheduler::default();
+        scheduler.set(Command::RobotTick, ms(100), start);
+        scheduler.set(Command::LaserTick, ms(150), start);
+        assert!(scheduler.due(start + ms(50)).is_empty());
+        assert_eq!(
+            scheduler.due(start + ms(300)),
+            vec![
+                Command::RobotTick,
+                Command::LaserTick,
+                Command::RobotTick,
+                Command::RobotTick,
+                Command::LaserTick,
+            ]
+        );
+        assert!(scheduler.due(start + ms(399)).is_empty());
+        assert_eq!(scheduler.due(start + ms(400)), vec![Command::RobotTick]);
+    }
+
+    #[test]
+    fn due_limits_catch_up() {
+        let start = Instant::now();
+        let mut scheduler = Scheduler::default();
+        scheduler.set(Command::RobotTick, ms(100), start);
+        let due = scheduler.due(start + ms(10_000));
+        assert_eq!(due.len() as u32, SCHEDULER_MAX_CATCH_UP + 1);
+        // 追いついた後はいつもの間隔に戻る
+        assert!(scheduler.due(start + ms(10_050)).is_empty());
+        assert_eq!(scheduler.due(start + ms(10_100)).len(), 1);
+    }
+
+    #[test]
+    fn set_keeps_next_time_and_reset_restarts() {
+        let start = Instant::now();
+        let mut scheduler = Scheduler::default();
+        scheduler.set(Command::RobotTick, ms(100), start);
+        scheduler.set(Command::RobotTick, ms(300), start + ms(50));
+        assert_eq!(scheduler.due(start + ms(100)), vec![Command::RobotTick]);
+        assert!(scheduler.due(start + ms(399)).is_empty());
+        scheduler.reset(start + ms(1_000));
+        assert!(scheduler.due(start + ms(1_299)).is_empty());
+        assert_eq!(scheduler.due(start + ms(1_300)), vec![Command::RobotTick]);
+    }
+}
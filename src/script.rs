@@ -0,0 +1,257 @@
+// スクリプト(Rhai)でロボットの動きやレベルのルールを書き換える
+// scripting featureを付けてビルドしたときだけ使える
+//
+// スクリプトで定義できる関数(どれも省略できる)
+//   spawn_robots(game)       -> [#{x: 1, y: 2, kind: "zigzag"}, ...]
+//                               組み込みのspawn_robotsの代わりにロボットを置く。kindを省くと普通のロボット
+//   move_<kind>(robot, game) -> #{x: 1, y: 2}
+//                               kindのロボットの次の位置。()を返すと普通に追いかける
+//   on_turn(game)            ロボットが動いた直後に毎ターン呼ぶ
+// スクリプトから呼べる関数
+//   add_junk(x, y)  add_robot(x, y, kind)
+// 引数の形
+//   game  : #{width, height, torus, topology, level, turn, players: [#{x, y, alive}], robots: [#{x, y, kind}],
+//             junks: [#{x, y}], cannon: #{x, y, direction, shooting}}
+//   robot : #{x, y, kind, target: #{x, y}}   targetは一番近い生きているプレイヤー
+
+use crate::model::*;
+use std::fmt;
+use std::rc::Rc;
+
+// featureなしでは作られることがない
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptAction {
+    AddJunk(Vec2),
+    AddRobot(Vec2, String),
+}
+
+pub struct Script {
+    path: String,
+    #[cfg(feature = "scripting")]
+    engine: rhai::Engine,
+    #[cfg(feature = "scripting")]
+    ast: rhai::AST,
+    // add_junkなどで頼まれたこと。呼び出しが終わるたびにtake_actionsで取り出す
+    #[cfg(feature = "scripting")]
+    actions: Rc<std::cell::RefCell<Vec<ScriptAction>>>,
+    #[cfg(not(feature = "scripting"))]
+    never: std::convert::Infallible,
+}
+
+impl fmt::Debug for Script {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Script({})", self.path)
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+impl Script {
+    pub fn load(path: &str) -> Result<Rc<Script>, String> {
+        Err(format!(
+            "cannot load {}: built without the scripting feature",
+            path
+        ))
+    }
+
+    pub fn spawn(&self, _game: &Game) -> Result<Option<Vec<(Vec2, String)>>, String> {
+        match self.never {}
+    }
+
+    pub fn move_robot(&self, _game: &Game, _index: usize) -> Result<Option<Vec2>, String> {
+        match self.never {}
+    }
+
+    pub fn on_turn(&self, _game: &Game) -> Result<(), String> {
+        match self.never {}
+    }
+
+    pub fn take_actions(&self) -> Vec<ScriptAction> {
+        match self.never {}
+    }
+}
+
+#[cfg(feature = "scripting")]
+pub const SCRIPT_MAX_OPERATIONS: u64 = 1_000_000;
+
+#[cfg(feature = "scripting")]
+impl Script {
+    pub fn load(path: &str) -> Result<Rc<Script>, String> {
+        let mut engine = rhai::Engine::new();
+        // 無限ループで止まらないようにする
+        engine.set_max_operations(SCRIPT_MAX_OPERATIONS);
+        let actions = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let a = Rc::clone(&actions);
+        engine.register_fn("add_junk", move |x: i64, y: i64| {
+            a.borrow_mut().push(ScriptAction::AddJunk(to_vec2(x, y)));
+        });
+        let a = Rc::clone(&actions);
+        engine.register_fn("add_robot", move |x: i64, y: i64, kind: &str| {
+            a.borrow_mut()
+                .push(ScriptAction::AddRobot(to_vec2(x, y), kind.to_string()));
+        });
+        let ast = engine
+            .compile_file(path.into())
+            .map_err(|e| format!("{}: {}", path, e))?;
+        println!("loaded script {}", path);
+        Ok(Rc::new(Script {
+            path: path.to_string(),
+            engine,
+            ast,
+            actions,
+        }))
+    }
+
+    fn has_fn(&self, name: &str) -> bool {
+        self.ast.iter_functions().any(|f| f.name == name)
+    }
+
+    fn call(&self, name: &str, args: impl rhai::FuncArgs) -> Result<rhai::Dynamic, String> {
+        self.engine
+            .call_fn(&mut rhai::Scope::new(), &self.ast, name, args)
+            .map_err(|e| format!("{}: {}", name, e))
+    }
+
+    // spawn_robotsが定義されていなければNone
+    pub fn spawn(&self, game: &Game) -> Result<Option<Vec<(Vec2, String)>>, String> {
+        if !self.has_fn("spawn_robots") {
+            return Ok(None);
+        }
+        let result = self.call("spawn_robots", (game_view(game),))?;
+        let array = result
+            .into_array()
+            .map_err(|t| format!("spawn_robots: expected an array, got {}", t))?;
+        array
+            .into_iter()
+            .map(|item| {
+                let map = item
+                    .try_cast::<rhai::Map>()
+                    .ok_or_else(|| "spawn_robots: expected #{x, y, kind}".to_string())?;
+                let kind = match map.get("kind") {
+                    Some(kind) => kind.clone().into_string().unwrap_or_default(),
+                    None => String::new(),
+                };
+                Ok((
+                    map_to_pos(&map).map_err(|e| format!("spawn_robots: {}", e))?,
+                    kind,
+                ))
+            })
+            .collect::<Result<Vec<_>, String>>()
+            .map(Some)
+    }
+
+    // kindのあるロボットの次の位置。()を返したらNone
+    pub fn move_robot(&self, game: &Game, index: usize) -> Result<Option<Vec2>, String> {
+        let robot = &game.robots[index];
+        let name = format!("move_{}", robot.kind);
+        if !self.has_fn(&name) {
+            return Err(format!(
+                "no function {} for robot kind {}",
+                name, robot.kind
+            ));
+        }
+        let result = self.call(&name, (robot_view(robot, game), game_view(game)))?;
+        if result.is_unit() {
+            return Ok(None);
+        }
+        let map = result
+            .try_cast::<rhai::Map>()
+            .ok_or_else(|| format!("{}: expected #{{x, y}}", name))?;
+        map_to_pos(&map)
+            .map(Some)
+            .map_err(|e| format!("{}: {}", name, e))
+    }
+
+    pub fn on_turn(&self, game: &Game) -> Result<(), String> {
+        if self.has_fn("on_turn") {
+            let _ = self.call("on_turn", (game_view(game),))?;
+        }
+        Ok(())
+    }
+
+    pub fn take_actions(&self) -> Vec<ScriptAction> {
+        self.actions.borrow_mut().drain(..).collect()
+    }
+}
+
+#[cfg(feature = "scripting")]
+fn to_vec2(x: i64, y: i64) -> Vec2 {
+    Vec2 {
+        x: x as i32,
+        y: y as i32,
+    }
+}
+
+#[cfg(feature = "scripting")]
+fn pos_view(pos: Vec2) -> rhai::Map {
+    let mut map = rhai::Map::new();
+    map.insert("x".into(), (pos.x as i64).into());
+    map.insert("y".into(), (pos.y as i64).into());
+    map
+}
+
+#[cfg(feature = "scripting")]
+fn map_to_pos(map: &rhai::Map) -> Result<Vec2, String> {
+    let get = |key: &str| {
+        map.get(key)
+            .and_then(|v| v.as_int().ok())
+            .ok_or_else(|| format!("missing {}", key))
+    };
+    let pos = to_vec2(get("x")?, get("y")?);
+    if !(0..FIELD_W).contains(&pos.x) || !(0..FIELD_H).contains(&pos.y) {
+        return Err(format!("({}, {}) is outside the field", pos.x, pos.y));
+    }
+    Ok(pos)
+}
+
+#[cfg(feature = "scripting")]
+fn robot_view(robot: &Robot, game: &Game) -> rhai::Map {
+    let mut map = pos_view(robot.pos);
+    map.insert("kind".into(), robot.kind.clone().into());
+    if let Some(target) = game.nearest_player(robot.pos) {
+        map.insert("target".into(), pos_view(game.players[target].pos).into());
+    }
+    map
+}
+
+#[cfg(feature = "scripting")]
+fn game_view(game: &Game) -> rhai::Map {
+    let mut map = rhai::Map::new();
+    map.insert("width".into(), (FIELD_W as i64).into());
+    map.insert("height".into(), (FIELD_H as i64).into());
+    map.insert("torus".into(), game.config.torus.into());
+    map.insert("topology".into(), game.config.topology.name().into());
+    map.insert("level".into(), (game.level as i64).into());
+    map.insert("turn".into(), (game.turn as i64).into());
+    let players: rhai::Array = game
+        .players
+        .iter()
+        .map(|p| {
+            let mut player = pos_view(p.pos);
+            player.insert("alive".into(), p.alive.into());
+            player.into()
+        })
+        .collect();
+    map.insert("players".into(), players.into());
+    let robots: rhai::Array = game
+        .robots
+        .iter()
+        .filter(|r| r.exist)
+        .map(|r| {
+            let mut robot = pos_view(r.pos);
+            robot.insert("kind".into(), r.kind.clone().into());
+            robot.into()
+        })
+        .collect();
+    map.insert("robots".into(), robots.into());
+    let junks: rhai::Array = game.junks.iter().map(|j| pos_view(j.pos).into()).collect();
+    map.insert("junks".into(), junks.into());
+    let mut cannon = pos_view(game.laser_cannon.pos);
+    cannon.insert(
+        "direction".into(),
+        format!("{:?}", game.laser_cannon.direction).into(),
+    );
+    cannon.insert("shooting".into(), game.laser_cannon.is_shooting.into());
+    map.insert("cannon".into(), cannon.into());
+    map
+}
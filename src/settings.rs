@@ -0,0 +1,139 @@
+// 画面の設定。$XDG_DATA_HOME/rust-robots/settings.txt に保存する
+//   particles = true
+//   theme = classic
+//   palette = normal
+//   shapes = false
+//   outline = false
+//   scale = integer
+//   fullscreen = false
+//   window_width = 1152
+//   window_height = 1208
+//   master_volume = 100
+//   sfx_volume = 100
+//   mute = false
+
+use crate::accessibility::Accessibility;
+use crate::audio::Volume;
+use crate::storage;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+// 論理サイズで描いた画面をウィンドウに合わせる方法
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum ScaleMode {
+    // 整数倍に拡大して、余りは黒い枠にする。ドットがつぶれない
+    #[default]
+    Integer,
+    // 縦横比を保ってウィンドウいっぱいに拡大する
+    Fit,
+}
+
+impl ScaleMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScaleMode::Integer => "integer",
+            ScaleMode::Fit => "fit",
+        }
+    }
+}
+
+impl std::str::FromStr for ScaleMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "integer" => Ok(ScaleMode::Integer),
+            "fit" => Ok(ScaleMode::Fit),
+            _ => Err(format!(
+                "unknown scale mode: {} (expected integer or fit)",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // 衝突の火花や画面の揺れ。すっきりした盤面がいい人は切れる
+    pub particles: bool,
+    // 最後に選んだテーマの名前
+    pub theme: String,
+    // 色の見分けにくさへの対応
+    pub accessibility: Accessibility,
+    pub scale: ScaleMode,
+    pub fullscreen: bool,
+    // 最後のウィンドウの大きさ。なければ画面に収まる整数倍にする
+    pub window_size: Option<(u32, u32)>,
+    pub volume: Volume,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            particles: true,
+            theme: "classic".to_string(),
+            accessibility: Accessibility::default(),
+            scale: ScaleMode::default(),
+            fullscreen: false,
+            window_size: None,
+            volume: Volume::default(),
+        }
+    }
+}
+
+impl Settings {
+    fn path() -> PathBuf {
+        storage::data_dir().join("settings.txt")
+    }
+
+    pub fn load() -> Self {
+        let Some(map) = storage::read_kv(&Settings::path()) else {
+            return Settings::default();
+        };
+        let default = Settings::default();
+        Settings {
+            particles: storage::get_parsed(&map, "particles").unwrap_or(default.particles),
+            theme: map.get("theme").cloned().unwrap_or(default.theme),
+            accessibility: Accessibility {
+                palette: storage::get_parsed(&map, "palette").unwrap_or_default(),
+                shapes: storage::get_parsed(&map, "shapes").unwrap_or_default(),
+                outline: storage::get_parsed(&map, "outline").unwrap_or_default(),
+            },
+            scale: storage::get_parsed(&map, "scale").unwrap_or_default(),
+            fullscreen: storage::get_parsed(&map, "fullscreen").unwrap_or_default(),
+            window_size: storage::get_parsed(&map, "window_width")
+                .zip(storage::get_parsed(&map, "window_height"))
+                .filter(|&(w, h)| w > 0 && h > 0),
+            volume: Volume {
+                master: storage::get_parsed(&map, "master_volume")
+                    .unwrap_or(default.volume.master)
+                    .min(100),
+                sfx: storage::get_parsed(&map, "sfx_volume")
+                    .unwrap_or(default.volume.sfx)
+                    .min(100),
+                muted: storage::get_parsed(&map, "mute").unwrap_or_default(),
+            },
+        }
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let mut map = BTreeMap::new();
+        map.insert("particles".to_string(), self.particles.to_string());
+        map.insert("theme".to_string(), self.theme.clone());
+        let a = &self.accessibility;
+        map.insert("palette".to_string(), a.palette.name().to_string());
+        map.insert("shapes".to_string(), a.shapes.to_string());
+        map.insert("outline".to_string(), a.outline.to_string());
+        map.insert("scale".to_string(), self.scale.name().to_string());
+        map.insert("fullscreen".to_string(), self.fullscreen.to_string());
+        if let Some((w, h)) = self.window_size {
+            map.insert("window_width".to_string(), w.to_string());
+            map.insert("window_height".to_string(), h.to_string());
+        }
+        let v = &self.volume;
+        map.insert("master_volume".to_string(), v.master.to_string());
+        map.insert("sfx_volume".to_string(), v.sfx.to_string());
+        map.insert("mute".to_string(), v.muted.to_string());
+        storage::write_kv(&Settings::path(), &map)
+    }
+}
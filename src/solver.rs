@@ -0,0 +1,337 @@
+use crate::model::*;
+use std::collections::{HashSet, VecDeque};
+
+pub const SOLVER_NODE_LIMIT: usize = 200_000;
+pub const REROLL_MAX: i32 = 100;
+// 作り直しの全部で調べる盤面の数の上限。1盤面に10マイクロ秒ほどかかるので、画面が数秒以上止まらないようにする
+pub const REROLL_NODE_LIMIT: usize = 400_000;
+
+// テレポート以外の手。テレポートはallow_teleportのときだけ試す
+const MOVES: [Command; 9] = [
+    Command::Left,
+    Command::Right,
+    Command::Down,
+    Command::Up,
+    Command::UpLeft,
+    Command::UpRight,
+    Command::DownLeft,
+    Command::DownRight,
+    Command::Wait,
+];
+
+// 道具と砲台の手。その盤面で使えるときだけ試す
+const ACTIONS: [Command; 7] = [
+    Command::UseItem(ItemKind::Emp),
+    Command::UseItem(ItemKind::JunkDropper),
+    Command::UseItem(ItemKind::Freeze),
+    Command::UseItem(ItemKind::CannonControl),
+    Command::TakeCannon,
+    Command::RotateCannon,
+    Command::FireCannon,
+];
+
+#[derive(Debug, Clone, Copy)]
+pub struct SolverConfig {
+    pub max_depth: i32,
+    pub allow_teleport: bool,
+    pub node_limit: usize,
+}
+
+impl SolverConfig {
+    pub fn new(max_depth: i32) -> Self {
+        SolverConfig {
+            max_depth,
+            allow_teleport: false,
+            node_limit: SOLVER_NODE_LIMIT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SolveResult {
+    // 最短手順
+    Solved(Vec<Command>),
+    // max_depth手以内では解けないことが確定した。2人のときの手は1人ずつの操作
+    Unsolvable,
+    // node_limitを超えたので打ち切った
+    GaveUp,
+}
+
+// 盤面の同一性を判定するためのキー
+// テレポートの結果は乱数の状態で決まるので、テレポートした回数も含める
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct StateKey {
+    // 拾ったシールドは持っているだけで効くので、持ち物も含める
+    players: Vec<(Vec2, bool, Vec<ItemKind>)>,
+    current_player: usize,
+    robots: Vec<Vec2>,
+    junks: Vec<Vec2>,
+    items: Vec<Vec2>,
+    laser_phase: i32,
+    // ロボットが止まっている残りのターン数
+    freeze: i32,
+    // 乗っ取られた砲台の状態
+    cannon: (Direction, Option<usize>, i32, i32),
+    teleports: i32,
+}
+
+impl StateKey {
+    fn new(game: &Game, teleports: i32) -> Self {
+        let mut robots: Vec<Vec2> = game
+            .robots
+            .iter()
+            .filter(|r| r.exist)
+            .map(|r| r.pos)
+            .collect();
+        robots.sort_by_key(|p| (p.x, p.y));
+        let mut junks: Vec<Vec2> = game.junks.iter().map(|j| j.pos).collect();
+        junks.sort_by_key(|p| (p.x, p.y));
+        StateKey {
+            players: game
+                .players
+                .iter()
+                .map(|p| (p.pos, p.alive, p.items.clone()))
+                .collect(),
+            current_player: game.current_player,
+            robots,
+            junks,
+            items: game.items.iter().map(|i| i.pos).collect(),
+            laser_phase: game.laser_cannon.turn % 8,
+            freeze: game.freeze,
+            cannon: (
+                game.laser_cannon.direction,
+                game.laser_cannon.controller,
+                game.laser_cannon.control_turns,
+                game.laser_cannon.cooldown,
+            ),
+            teleports,
+        }
+    }
+}
+
+struct Node {
+    parent: usize,
+    command: Command,
+}
+
+// 幅優先探索で、現在のレベルをクリアする最短の手順を探す
+pub fn solve(game: &Game, config: &SolverConfig) -> SolveResult {
+    search(game, config).0
+}
+
+// solveと同じ。調べた盤面の数も返す
+fn search(game: &Game, config: &SolverConfig) -> (SolveResult, usize) {
+    if game.is_clear {
+        return (SolveResult::Solved(Vec::new()), 0);
+    }
+    if game.is_over {
+        return (SolveResult::Unsolvable, 0);
+    }
+
+    // 六角形の盤面ではUpとDownは使えない
+    let mut commands: Vec<Command> = MOVES
+        .iter()
+        .copied()
+        .filter(|c| {
+            c.direction()
+                .is_none_or(|d| game.topology().directions().contains(&d))
+        })
+        .collect();
+    if config.allow_teleport {
+        commands.push(Command::Teleport);
+    }
+    commands.extend(ACTIONS);
+
+    let mut root = game.clone();
+    root.requested_sounds.clear();
+    root.events.clear();
+    // 探索では盤面が全部わかっている。視界の計算は重いので止める
+    root.config.vision = 0;
+    root.vision.clear();
+    // リアルタイムモードでもターン制として解く
+    root.config.realtime = 0;
+
+    // nodes[0]は根。親をたどって手順を復元する
+    let mut nodes: Vec<Node> = vec![Node {
+        parent: 0,
+        command: Command::None,
+    }];
+    let mut visited: HashSet<StateKey> = HashSet::new();
+    visited.insert(StateKey::new(&root, 0));
+    let mut queue: VecDeque<(usize, i32, i32, Game)> = VecDeque::new();
+    queue.push_back((0, 0, 0, root));
+
+    while let Some((index, depth, teleports, state)) = queue.pop_front() {
+        if depth >= config.max_depth {
+            continue;
+        }
+        for command in commands.iter().filter(|c| state.is_available(**c)) {
+            let mut next = state.clone();
+            next.update(*command);
+            next.requested_sounds.clear();
+            next.events.clear();
+            if next.is_over {
+                continue;
+            }
+            let teleports = if *command == Command::Teleport {
+                teleports + 1
+            } else {
+                teleports
+            };
+            if !visited.insert(StateKey::new(&next, teleports)) {
+                continue;
+            }
+            nodes.push(Node {
+                parent: index,
+                command: *command,
+            });
+            let child = nodes.len() - 1;
+            if next.is_clear {
+                return (SolveResult::Solved(trace(&nodes, child)), nodes.len());
+            }
+            if nodes.len() > config.node_limit {
+                return (SolveResult::GaveUp, nodes.len());
+            }
+            queue.push_back((child, depth + 1, teleports, next));
+        }
+    }
+
+    (SolveResult::Unsolvable, nodes.len())
+}
+
+fn trace(nodes: &[Node], mut index: usize) -> Vec<Command> {
+    let mut result = Vec::new();
+    while index != 0 {
+        result.push(nodes[index].command);
+        index = nodes[index].parent;
+    }
+    result.reverse();
+    result
+}
+
+// 解けることが確認できるまで現在のレベルの盤面を作り直す
+// REROLL_MAX回試すか、調べた盤面がREROLL_NODE_LIMITに達したら、最後の盤面のままにしてErrを返す
+pub fn reroll_until_solvable(
+    game: &mut Game,
+    config: &SolverConfig,
+) -> Result<Vec<Command>, String> {
+    let mut searched = 0;
+    for _ in 0..REROLL_MAX {
+        let config = SolverConfig {
+            node_limit: config.node_limit.min(REROLL_NODE_LIMIT - searched),
+            ..*config
+        };
+        let (result, nodes) = search(game, &config);
+        if let SolveResult::Solved(commands) = result {
+            return Ok(commands);
+        }
+        searched += nodes;
+        if searched >= REROLL_NODE_LIMIT {
+            break;
+        }
+        game.setup_level();
+    }
+    Err(format!(
+        "could not generate a level solvable within {} commands",
+        config.max_depth
+    ))
+}
+
+pub fn commands_to_string(commands: &[Command]) -> String {
+    commands.iter().filter_map(|c| c.to_char()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(x: i32, y: i32) -> Vec2 {
+        Vec2 { x, y }
+    }
+
+    // 決まった位置にプレイヤーとロボットだけを置いた盤面。砲台は遠くに置いて、しばらく撃たない
+    fn tiny_level(player: Vec2, robots: &[Vec2]) -> Game {
+        let mut game = Game::with_seed(1, GameConfig::default());
+        game.players[0].pos = player;
+        game.players[0].prev_pos = player;
+        game.players[0].items.clear();
+        game.robots = robots
+            .iter()
+            .map(|pos| Robot {
+                pos: *pos,
+                prev_pos: *pos,
+                exist: true,
+                target: 0,
+                kind: String::new(),
+            })
+            .collect();
+        game.initial_robot_count = robots.len() as i32;
+        game.junks.clear();
+        game.items.clear();
+        game.laser_cannon.pos = v(30, 30);
+        game.laser_cannon.turn = 0;
+        game
+    }
+
+    fn replay(game: &Game, commands: &[Command]) -> Game {
+        let mut game = game.clone();
+        for command in commands {
+            game.update(*command);
+        }
+        game
+    }
+
+    // 2体のロボットがプレイヤーに近づくと同じマスでぶつかる
+    fn colliding_pair() -> Game {
+        tiny_level(v(5, 5), &[v(4, 7), v(6, 7)])
+    }
+
+    #[test]
+    fn solves_in_one_turn() {
+        let game = colliding_pair();
+        let SolveResult::Solved(commands) = solve(&game, &SolverConfig::new(5)) else {
+            panic!("not solved");
+        };
+        assert_eq!(commands.len(), 1);
+        assert!(replay(&game, &commands).is_clear);
+    }
+
+    #[test]
+    fn freeze_delays_the_solution() {
+        // 止まっている間はロボットがぶつからないので、1ターン余計にかかる
+        let mut game = colliding_pair();
+        game.freeze = 1;
+        let SolveResult::Solved(commands) = solve(&game, &SolverConfig::new(5)) else {
+            panic!("not solved");
+        };
+        assert_eq!(commands.len(), 2);
+        assert!(replay(&game, &commands).is_clear);
+    }
+
+    #[test]
+    fn state_key_includes_freeze() {
+        let game = colliding_pair();
+        let mut frozen = game.clone();
+        frozen.freeze = 3;
+        assert_eq!(StateKey::new(&game, 0), StateKey::new(&game.clone(), 0));
+        assert_ne!(StateKey::new(&game, 0), StateKey::new(&frozen, 0));
+    }
+
+    #[test]
+    fn uses_items() {
+        // 1体だけではぶつからないので、EMPを使うしかない
+        let mut game = tiny_level(v(5, 5), &[v(5, 8)]);
+        game.players[0].items.push(ItemKind::Emp);
+        let SolveResult::Solved(commands) = solve(&game, &SolverConfig::new(5)) else {
+            panic!("not solved");
+        };
+        assert!(commands.contains(&Command::UseItem(ItemKind::Emp)));
+        assert!(replay(&game, &commands).is_clear);
+    }
+
+    #[test]
+    fn unsolvable_within_depth() {
+        let game = tiny_level(v(5, 5), &[v(5, 8)]);
+        assert_eq!(solve(&game, &SolverConfig::new(3)), SolveResult::Unsolvable);
+    }
+}
@@ -0,0 +1,329 @@
+// 観戦用サーバーとクライアント
+//
+// サーバーはターンごとに盤面のスナップショットを1行のJSONにして、つないでいる全員に送る
+// (newline-delimited JSON)。途中からつないだクライアントには最新のスナップショットを最初に送る
+// 何もしなければ127.0.0.1で待つので、ほかのマシンから見るには--serve-addressで開ける
+//   {"turn":3,"level":1,"score":2,...,"players":[...],"robots":[...],"junks":[...],
+//    "laser_cannon":{...},"events":[{"type":"RobotDestroyed","pos":{"x":1,"y":2},"cause":"Junk"}]}
+// laser_cannonのis_shooting/begin/endは最初の形のまま。撃ったマスはbeamにも入っている
+//
+// 霧のモードでボットに渡すときは、そのプレイヤーに見えているものだけにする(Snapshot::observe)
+//   players         : 見えていない他のプレイヤーはnull
+//   robots, junks   : 見えているものだけ
+//   score, stats    : 見えない所で壊れたロボットがわかってしまうので、得点と壊した数は0にする
+//   events          : 他のプレイヤーのことや位置のあるものは、見えているマスで起きたものだけ
+//                     道具や砲台のイベントは、そのプレイヤーが見えているときだけ
+//                     LaserFiredのkilledは見えているロボットだけを数え、beamは見えているマスだけ
+//   laser_cannon    : まだ見ていなければnull。レーザーは見えているマスだけで、
+//                     一部しか見えていなければbegin/endは0
+//   visible         : 1行1文字列で、見えているマスが'#'、見えていないマスが'.'
+//   remembered_robots : 今は見えていないが、ロボットを最後に見た位置
+
+use crate::model::*;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// 書き込めないまま、この時間がたったクライアントは切る
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// つないでいるクライアントの書き込みスレッドと最新のスナップショット
+type Clients = (Vec<Sender<String>>, Option<String>);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub seed: u64,
+    pub config: GameConfig,
+    pub turn: i32,
+    pub level: i32,
+    pub score: i32,
+    pub is_over: bool,
+    pub is_clear: bool,
+    pub initial_robot_count: i32,
+    pub current_player: usize,
+    pub stats: LevelStats,
+    // 霧のモードで見えていない他のプレイヤーはNone
+    pub players: Vec<Option<Player>>,
+    pub robots: Vec<Robot>,
+    pub junks: Vec<Junk>,
+    #[serde(default)]
+    pub items: Vec<Item>,
+    #[serde(default)]
+    pub freeze: i32,
+    pub laser_cannon: Option<LaserCannon>,
+    // 前のスナップショットから後に起きたこと
+    pub events: Vec<GameEvent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub visible: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remembered_robots: Vec<Vec2>,
+}
+
+impl Snapshot {
+    pub fn new(game: &Game) -> Self {
+        Snapshot {
+            seed: game.seed,
+            config: game.config,
+            turn: game.turn,
+            level: game.level,
+            score: game.score,
+            is_over: game.is_over,
+            is_clear: game.is_clear,
+            initial_robot_count: game.initial_robot_count,
+            current_player: game.current_player,
+            stats: game.stats.clone(),
+            players: game.players.iter().cloned().map(Some).collect(),
+            robots: game.robots.clone(),
+            junks: game.junks.clone(),
+            items: game.items.clone(),
+            freeze: game.freeze,
+            laser_cannon: Some(game.laser_cannon.clone()),
+            events: game.events.clone(),
+            visible: None,
+            remembered_robots: Vec::new(),
+        }
+    }
+
+    // playerに見えているものだけのスナップショット。霧のモードでなければnewと同じ
+    pub fn observe(game: &Game, player: usize) -> Self {
+        let mut snapshot = Snapshot::new(game);
+        if game.vision.is_empty() {
+            return snapshot;
+        }
+        let viewer = Some(player);
+        let visible = |pos: Vec2| game.is_visible(viewer, pos);
+        for (i, other) in snapshot.players.iter_mut().enumerate() {
+            if i != player && !other.as_ref().is_some_and(|p| visible(p.pos)) {
+                *other = None;
+            }
+        }
+        snapshot.score = 0;
+        for p in snapshot.players.iter_mut().flatten() {
+            p.score = 0;
+        }
+        hide_kills(&mut snapshot.stats);
+        snapshot.robots.retain(|r| visible(r.pos));
+        snapshot.junks.retain(|j| visible(j.pos));
+        snapshot.items.retain(|i| visible(i.pos));
+        snapshot.laser_cannon = match &mut snapshot.laser_cannon {
+            Some(cannon) if game.is_cannon_seen(viewer) => {
+                let length = cannon.beam.len();
+                cannon.beam.retain(|pos| visible(*pos));
+                // begin..endから見えないマスまでレーザーが届いたことがわかってしまう
+                if cannon.beam.len() != length {
+                    (cannon.begin, cannon.end) = (0, 0);
+                }
+                Some(cannon.clone())
+            }
+            _ => None,
+        };
+        // 自分のことはいつもわかる。他のプレイヤーのことは見えているときだけ
+        let seen = |p: usize, pos: &[Vec2]| p == player || pos.iter().all(|pos| visible(*pos));
+        let mut laser_kills = 0;
+        snapshot.events.retain_mut(|e| match e {
+            GameEvent::PlayerMoved { player, from, to }
+            | GameEvent::Teleported { player, from, to } => seen(*player, &[*from, *to]),
+            GameEvent::PlayerDied { player, pos, .. } => seen(*player, &[*pos]),
+            GameEvent::ItemPickedUp { player, .. }
+            | GameEvent::ItemUsed { player, .. }
+            | GameEvent::CannonTaken { player } => seen(*player, &[game.players[*player].pos]),
+            GameEvent::MoveBlocked { pos } => visible(*pos),
+            GameEvent::RobotDestroyed { pos, cause, .. } => {
+                let shown = visible(*pos);
+                if shown && *cause == KillCause::Laser {
+                    laser_kills += 1;
+                }
+                shown
+            }
+            // レーザーで壊れたロボットのイベントはLaserFiredより前に並んでいる
+            GameEvent::LaserFired { killed, beam } => {
+                *killed = std::mem::take(&mut laser_kills);
+                beam.retain(|cell| visible(*cell));
+                game.is_cannon_seen(viewer)
+            }
+            GameEvent::LevelCleared { stats } => {
+                hide_kills(stats);
+                true
+            }
+            _ => true,
+        });
+        snapshot.visible = Some(
+            (0..FIELD_H)
+                .map(|y| {
+                    (0..FIELD_W)
+                        .map(|x| if visible(Vec2 { x, y }) { '#' } else { '.' })
+                        .collect()
+                })
+                .collect(),
+        );
+        snapshot.remembered_robots = game.remembered_robots(viewer);
+        snapshot
+    }
+
+    // 描画できるように盤面に書き戻す
+    pub fn apply(self, game: &mut Game) {
+        game.seed = self.seed;
+        game.config = self.config;
+        game.turn = self.turn;
+        game.level = self.level;
+        game.score = self.score;
+        game.is_over = self.is_over;
+        game.is_clear = self.is_clear;
+        game.initial_robot_count = self.initial_robot_count;
+        game.current_player = self.current_player;
+        game.stats = self.stats;
+        // 届かなかったプレイヤーは前のまま
+        let players = self
+            .players
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, p)| p.or_else(|| game.players.get(i).cloned()))
+            .collect();
+        game.players = players;
+        game.robots = self.robots;
+        game.junks = self.junks;
+        game.items = self.items;
+        game.freeze = self.freeze;
+        if let Some(laser_cannon) = self.laser_cannon {
+            game.laser_cannon = laser_cannon;
+        }
+        // prev_posが届いたので、描画側で動きを見せる
+        game.motion += 1;
+        // 観戦している人には霧のモードでも全部見せる
+        game.vision.clear();
+        game.events.extend(self.events);
+    }
+}
+
+// 霧のモードで渡さない、壊したロボットの数
+fn hide_kills(stats: &mut LevelStats) {
+    stats.killed_by_collision = 0;
+    stats.killed_by_junk = 0;
+    stats.killed_by_laser = 0;
+    stats.killed_by_item = 0;
+}
+
+pub struct SpectatorServer {
+    sender: Sender<String>,
+}
+
+impl SpectatorServer {
+    pub fn start(address: &str, port: u16) -> Result<Self, String> {
+        let listener = TcpListener::bind((address, port)).map_err(|e| e.to_string())?;
+        println!("spectator server listening on {}:{}", address, port);
+        let shared: Arc<Mutex<Clients>> = Arc::new(Mutex::new((Vec::new(), None)));
+
+        let accept_shared = Arc::clone(&shared);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let client = spawn_writer(stream);
+                let mut shared = accept_shared.lock().unwrap();
+                if let Some(line) = &shared.1 {
+                    let _ = client.send(line.clone());
+                }
+                shared.0.push(client);
+            }
+        });
+
+        // ゲームを止めないように、配るのは別スレッドで行う
+        // ロックしている間は書き込みスレッドに渡すだけなので、遅いクライアントがいてもほかのクライアントは待たない
+        let (sender, receiver) = mpsc::channel::<String>();
+        thread::spawn(move || {
+            for line in receiver {
+                let mut shared = shared.lock().unwrap();
+                shared.0.retain(|client| client.send(line.clone()).is_ok());
+                shared.1 = Some(line);
+            }
+        });
+
+        Ok(SpectatorServer { sender })
+    }
+
+    pub fn send(&self, game: &Game) {
+        match serde_json::to_string(&Snapshot::new(game)) {
+            Ok(line) => {
+                let _ = self.sender.send(line);
+            }
+            Err(e) => println!("cannot serialize snapshot: {}", e),
+        }
+    }
+}
+
+// クライアント1人に書き込むスレッドを立てて、送る行を渡すSenderを返す
+// 切れたり止まったりして書き込めなくなるとスレッドが終わり、Senderに送れなくなる
+fn spawn_writer(mut stream: TcpStream) -> Sender<String> {
+    let (sender, receiver) = mpsc::channel::<String>();
+    thread::spawn(move || {
+        if stream.set_write_timeout(Some(WRITE_TIMEOUT)).is_err() {
+            return;
+        }
+        for line in receiver {
+            if writeln!(stream, "{}", line).is_err() {
+                break;
+            }
+        }
+    });
+    sender
+}
+
+pub struct SpectatorClient {
+    receiver: Receiver<String>,
+    pub connected: bool,
+}
+
+impl SpectatorClient {
+    pub fn connect(addr: &str) -> Result<Self, String> {
+        let stream = TcpStream::connect(addr).map_err(|e| e.to_string())?;
+        println!("spectating {}", addr);
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stream).lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(SpectatorClient {
+            receiver,
+            connected: true,
+        })
+    }
+
+    // 届いたスナップショットを順に盤面に反映する
+    pub fn poll(&mut self, game: &mut Game) {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(line) => match serde_json::from_str::<Snapshot>(&line) {
+                    Ok(snapshot) => snapshot.apply(game),
+                    Err(e) => println!("invalid snapshot: {}", e),
+                },
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    if self.connected {
+                        println!("spectator stream closed");
+                    }
+                    self.connected = false;
+                    return;
+                }
+            }
+        }
+    }
+
+    pub fn status_line(&self) -> String {
+        if self.connected {
+            "SPECTATING".to_string()
+        } else {
+            "SPECTATING: DISCONNECTED".to_string()
+        }
+    }
+}
@@ -0,0 +1,313 @@
+// スプライトシート
+//
+// 画像をコマに分けて、コマを並べたアニメーションを決める。画像と同じ場所の NAME.sheet に書く
+//   image = robot.png
+//   grid = 16 16                  コマの大きさ。左上から右へ、段ごとに0, 1, 2...と番号を付ける
+//   frame = 0 0 16 16             gridの代わりにコマの矩形を1つずつ書いてもよい
+//   idle = 500 loop 0 1           アニメーション = 1コマのミリ秒 loop|once コマ番号...
+//   walk = 100 loop 2 3
+//   death = 120 once 4 5 6
+// シートのない画像は、最初の正方形のコマだけを使う
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum AnimKind {
+    // 止まっているとき
+    Idle,
+    // 動いているとき。砲台は回っているとき
+    Walk,
+    // 壊れたとき・死んだとき
+    Death,
+}
+
+impl AnimKind {
+    fn from_key(key: &str) -> Option<AnimKind> {
+        match key {
+            "idle" => Some(AnimKind::Idle),
+            "walk" => Some(AnimKind::Walk),
+            "death" => Some(AnimKind::Death),
+            _ => None,
+        }
+    }
+}
+
+// 画像の中のコマの位置
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FrameRect {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Animation {
+    frames: Vec<usize>,
+    frame_ms: u32,
+    looping: bool,
+}
+
+impl Animation {
+    // 始まってからmsミリ秒のコマ。1回だけのものは最後のコマで止まる
+    fn frame_at(&self, ms: u32) -> usize {
+        let step = (ms / self.frame_ms.max(1)) as usize;
+        if self.looping {
+            self.frames[step % self.frames.len()]
+        } else {
+            self.frames[step.min(self.frames.len() - 1)]
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SpriteSheet {
+    pub image: String,
+    grid: Option<(u32, u32)>,
+    frames: Vec<FrameRect>,
+    animations: HashMap<AnimKind, Animation>,
+}
+
+impl SpriteSheet {
+    // sourceはエラーに出すファイル名
+    pub fn parse(source: &str, text: &str) -> Result<SpriteSheet, String> {
+        let mut sheet = SpriteSheet {
+            image: String::new(),
+            grid: None,
+            frames: Vec::new(),
+            animations: HashMap::new(),
+        };
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let result = match line.split_once('=') {
+                Some((key, value)) => sheet.set(key.trim(), value.trim()),
+                None => Err(format!("expected key = value: {}", line)),
+            };
+            result.map_err(|e| format!("sheet {}:{}: {}", source, i + 1, e))?;
+        }
+        if sheet.image.is_empty() {
+            return Err(format!("sheet {}: image is not set", source));
+        }
+        if sheet.grid.is_some() != sheet.frames.is_empty() {
+            return Err(format!("sheet {}: set either grid or frame", source));
+        }
+        Ok(sheet)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let numbers = || -> Result<Vec<u32>, String> {
+            value
+                .split_whitespace()
+                .map(|n| {
+                    n.parse()
+                        .map_err(|_| format!("{}: not a number: {}", key, n))
+                })
+                .collect()
+        };
+        if let Some(kind) = AnimKind::from_key(key) {
+            let mut words = value.split_whitespace();
+            let frame_ms = words
+                .next()
+                .and_then(|ms| ms.parse().ok())
+                .filter(|ms| *ms > 0)
+                .ok_or_else(|| format!("{}: expected milliseconds per frame: {}", key, value))?;
+            let looping = match words.next() {
+                Some("loop") => true,
+                Some("once") => false,
+                _ => return Err(format!("{}: expected loop or once: {}", key, value)),
+            };
+            let frames = words
+                .map(|n| {
+                    n.parse()
+                        .map_err(|_| format!("{}: not a frame number: {}", key, n))
+                })
+                .collect::<Result<Vec<usize>, String>>()?;
+            if frames.is_empty() {
+                return Err(format!("{}: no frames", key));
+            }
+            self.animations.insert(
+                kind,
+                Animation {
+                    frames,
+                    frame_ms,
+                    looping,
+                },
+            );
+            return Ok(());
+        }
+        match key {
+            "image" if !value.is_empty() => self.image = value.to_string(),
+            "grid" => match numbers()?[..] {
+                [w, h] if w > 0 && h > 0 => self.grid = Some((w, h)),
+                _ => return Err(format!("grid must be WIDTH HEIGHT: {}", value)),
+            },
+            "frame" => match numbers()?[..] {
+                [x, y, w, h] if w > 0 && h > 0 => self.frames.push(FrameRect { x, y, w, h }),
+                _ => return Err(format!("frame must be X Y WIDTH HEIGHT: {}", value)),
+            },
+            "image" => return Err("image must not be empty".to_string()),
+            _ => return Err(format!("unknown key: {}", key)),
+        }
+        Ok(())
+    }
+
+    // 画像の大きさに合わせてgridをコマに分け、コマが画像に収まっているかを確かめる
+    pub fn resolve(&mut self, width: u32, height: u32) -> Result<(), String> {
+        if let Some((w, h)) = self.grid {
+            self.frames = (0..height / h)
+                .flat_map(|row| {
+                    (0..width / w).map(move |column| FrameRect {
+                        x: column * w,
+                        y: row * h,
+                        w,
+                        h,
+                    })
+                })
+                .collect();
+        }
+        // コマがないとframeで取り出せない
+        if self.frames.is_empty() {
+            return Err(format!(
+                "{}: no frames fit in the {}x{} image",
+                self.image, width, height
+            ));
+        }
+        if let Some(frame) = self
+            .frames
+            .iter()
+            .find(|f| f.x + f.w > width || f.y + f.h > height)
+        {
+            return Err(format!(
+                "{}: frame {} {} {} {} is outside the {}x{} image",
+                self.image, frame.x, frame.y, frame.w, frame.h, width, height
+            ));
+        }
+        for animation in self.animations.values() {
+            if let Some(n) = animation.frames.iter().find(|n| **n >= self.frames.len()) {
+                return Err(format!(
+                    "{}: frame {} does not exist ({} frames)",
+                    self.image,
+                    n,
+                    self.frames.len()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn has(&self, kind: AnimKind) -> bool {
+        self.animations.contains_key(&kind)
+    }
+
+    // kindのアニメーションの始まってからmsミリ秒のコマ。なければidle、それもなければ最初のコマ
+    pub fn frame(&self, kind: AnimKind, ms: u32) -> FrameRect {
+        let index = self
+            .animations
+            .get(&kind)
+            .or_else(|| self.animations.get(&AnimKind::Idle))
+            .map(|a| a.frame_at(ms))
+            .unwrap_or(0);
+        self.frames[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        SpriteSheet::parse("robot.sheet", text).unwrap_err()
+    }
+
+    #[test]
+    fn grid_and_animations() {
+        let mut sheet = SpriteSheet::parse(
+            "robot.sheet",
+            "image = robot.png\ngrid = 16 16\nidle = 500 loop 0 1\ndeath = 100 once 2 3\n",
+        )
+        .unwrap();
+        sheet.resolve(64, 16).unwrap();
+        assert!(sheet.has(AnimKind::Idle));
+        assert!(!sheet.has(AnimKind::Walk));
+        let at = |x| FrameRect {
+            x,
+            y: 0,
+            w: 16,
+            h: 16,
+        };
+        assert_eq!(sheet.frame(AnimKind::Idle, 0), at(0));
+        assert_eq!(sheet.frame(AnimKind::Idle, 1_500), at(16));
+        // 1回だけのものは最後のコマで止まる
+        assert_eq!(sheet.frame(AnimKind::Death, 1_000), at(48));
+        // walkがなければidleを使う
+        assert_eq!(sheet.frame(AnimKind::Walk, 500), at(16));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(error("grid = 16 16"), "sheet robot.sheet: image is not set");
+        assert_eq!(
+            error("image = a.png"),
+            "sheet robot.sheet: set either grid or frame"
+        );
+        assert_eq!(
+            error("image = a.png\ngrid = 16 16\nframe = 0 0 16 16"),
+            "sheet robot.sheet: set either grid or frame"
+        );
+        assert_eq!(
+            error("image = a.png\ngrid = 16"),
+            "sheet robot.sheet:2: grid must be WIDTH HEIGHT: 16"
+        );
+        assert_eq!(
+            error("image = a.png\nframe = 0 0 x 16"),
+            "sheet robot.sheet:2: frame: not a number: x"
+        );
+        assert_eq!(
+            error("idle = 0 loop 0"),
+            "sheet robot.sheet:1: idle: expected milliseconds per frame: 0 loop 0"
+        );
+        assert_eq!(
+            error("walk = 100 twice 0"),
+            "sheet robot.sheet:1: walk: expected loop or once: 100 twice 0"
+        );
+        assert_eq!(
+            error("death = 100 once"),
+            "sheet robot.sheet:1: death: no frames"
+        );
+        assert_eq!(
+            error("run = 100 loop 0"),
+            "sheet robot.sheet:1: unknown key: run"
+        );
+        assert_eq!(
+            error("image"),
+            "sheet robot.sheet:1: expected key = value: image"
+        );
+    }
+
+    #[test]
+    fn resolve_errors() {
+        let mut sheet =
+            SpriteSheet::parse("robot.sheet", "image = a.png\nframe = 8 0 16 16").unwrap();
+        assert_eq!(
+            sheet.resolve(16, 16).unwrap_err(),
+            "a.png: frame 8 0 16 16 is outside the 16x16 image"
+        );
+        let mut sheet = SpriteSheet::parse(
+            "robot.sheet",
+            "image = a.png\ngrid = 16 16\nidle = 100 loop 0 2",
+        )
+        .unwrap();
+        assert_eq!(
+            sheet.resolve(32, 16).unwrap_err(),
+            "a.png: frame 2 does not exist (2 frames)"
+        );
+        let mut sheet = SpriteSheet::parse("robot.sheet", "image = a.png\ngrid = 32 32").unwrap();
+        assert_eq!(
+            sheet.resolve(16, 16).unwrap_err(),
+            "a.png: no frames fit in the 16x16 image"
+        );
+    }
+}
@@ -0,0 +1,50 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const APP_NAME: &str = "rust-robots";
+
+// $XDG_DATA_HOME/rust-robots (なければ ~/.local/share/rust-robots)
+pub fn data_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("XDG_DATA_HOME").filter(|d| !d.is_empty()) {
+        return PathBuf::from(dir).join(APP_NAME);
+    }
+    if let Some(home) = std::env::var_os("HOME").filter(|d| !d.is_empty()) {
+        return PathBuf::from(home)
+            .join(".local")
+            .join("share")
+            .join(APP_NAME);
+    }
+    PathBuf::from(".").join(APP_NAME)
+}
+
+// "key = value" 形式のファイルを読む。#で始まる行はコメント
+pub fn read_kv(path: &Path) -> Option<BTreeMap<String, String>> {
+    let text = fs::read_to_string(path).ok()?;
+    let mut map = BTreeMap::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            map.insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+    Some(map)
+}
+
+pub fn write_kv(path: &Path, map: &BTreeMap<String, String>) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    }
+    let mut text = String::new();
+    for (key, value) in map {
+        text.push_str(&format!("{} = {}\n", key, value));
+    }
+    fs::write(path, text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+pub fn get_parsed<T: std::str::FromStr>(map: &BTreeMap<String, String>, key: &str) -> Option<T> {
+    map.get(key).and_then(|v| v.parse().ok())
+}
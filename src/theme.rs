@@ -0,0 +1,462 @@
+// 見た目のテーマ
+//
+// テーマファイルは "key = value" 形式で、書かなかった項目はclassicと同じになる
+//   name = retro
+//   background = #001000        色は#rrggbb
+//   win = #ff8080 #80ff80       winだけは色を並べて書く
+//   robot_look = glyph +        cell(マスを塗る) / sprite ファイル名 / glyph 1文字 / shape 形
+//   font = boxfont2.ttf
+//   font_size = 24
+// 組み込みのテーマはresources/theme/にあり、$XDG_DATA_HOME/rust-robots/themes/*.theme も読む
+// 読み込むときに全部の項目を確かめて、どのファイルの何行目がおかしいかをエラーにする
+
+use crate::model::*;
+use crate::storage;
+use std::fs;
+use std::path::Path;
+
+pub const FONT_SIZE_MIN: u16 = 8;
+pub const FONT_SIZE_MAX: u16 = 64;
+pub const LASER_WIDTH_MAX: u32 = CELL_W as u32;
+
+const BUILTIN_THEMES: [(&str, &str); 5] = [
+    ("classic", include_str!("../resources/theme/classic.theme")),
+    ("ascii", include_str!("../resources/theme/ascii.theme")),
+    (
+        "high_contrast",
+        include_str!("../resources/theme/high_contrast.theme"),
+    ),
+    ("retro", include_str!("../resources/theme/retro.theme")),
+    (
+        "animated",
+        include_str!("../resources/theme/animated.theme"),
+    ),
+];
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+impl std::str::FromStr for Rgb {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s
+            .strip_prefix('#')
+            .filter(|h| h.len() == 6 && h.chars().all(|c| c.is_ascii_hexdigit()))
+            .ok_or_else(|| format!("invalid color: {} (expected #rrggbb)", s))?;
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+        Ok(Rgb(channel(0), channel(2), channel(4)))
+    }
+}
+
+// マスの中に塗る形。色で見分けにくい人のために、ものごとに形を変えられる
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Shape {
+    Circle,
+    Diamond,
+    Triangle,
+    // ×印
+    Cross,
+}
+
+impl std::str::FromStr for Shape {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "circle" => Ok(Shape::Circle),
+            "diamond" => Ok(Shape::Diamond),
+            "triangle" => Ok(Shape::Triangle),
+            "cross" => Ok(Shape::Cross),
+            _ => Err(format!(
+                "unknown shape: {} (expected circle, diamond, triangle or cross)",
+                s
+            )),
+        }
+    }
+}
+
+// ものの描き方
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Look {
+    // マスを塗る
+    Cell,
+    Shape(Shape),
+    // resources/image/の画像
+    Sprite(String),
+    // フォントの1文字
+    Glyph(char),
+    // 道具ごとの文字(e s j f c)。道具にだけ使える
+    Letters,
+}
+
+impl Look {
+    fn parse(value: &str, letters: bool) -> Result<Self, String> {
+        let mut words = value.split_whitespace();
+        let look = match (words.next(), words.next()) {
+            (Some("cell"), None) => Look::Cell,
+            (Some("letters"), None) if letters => Look::Letters,
+            (Some("sprite"), Some(file)) => Look::Sprite(file.to_string()),
+            (Some("shape"), Some(shape)) => Look::Shape(shape.parse()?),
+            (Some("glyph"), Some(glyph)) if glyph.chars().count() == 1 => {
+                Look::Glyph(glyph.chars().next().unwrap())
+            }
+            _ => {
+                return Err(format!(
+                    "invalid look: {} (expected cell, shape NAME, sprite FILE, glyph CHAR{})",
+                    value,
+                    if letters { " or letters" } else { "" }
+                ))
+            }
+        };
+        if words.next().is_some() {
+            return Err(format!("invalid look: {}", value));
+        }
+        Ok(look)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub name: String,
+    pub background: Rgb,
+    // 六角形の盤面のマス目
+    pub grid: Rgb,
+    pub hud: Rgb,
+    pub text: Rgb,
+    pub players: [Rgb; 2],
+    pub robot: Rgb,
+    pub robot_frozen: Rgb,
+    pub robot_remembered: Rgb,
+    pub junk: Rgb,
+    pub cannon: Rgb,
+    pub laser: Rgb,
+    pub explosion: Rgb,
+    pub game_over: Rgb,
+    // パーティクル。ロボットが壊れたときの火花と破片、レーザーの焦げ跡
+    pub spark: Rgb,
+    pub debris: Rgb,
+    pub scorch: Rgb,
+    // YOU WIN! の文字。フレームごとに順に変える
+    pub win: Vec<Rgb>,
+    // 実績のお知らせ
+    pub toast: Rgb,
+    pub toast_title: Rgb,
+    pub toast_text: Rgb,
+    // ITEM_KINDSの順
+    pub items: [Rgb; 5],
+    pub player_look: Look,
+    pub robot_look: Look,
+    pub junk_look: Look,
+    pub cannon_look: Look,
+    pub item_look: Look,
+    pub font: String,
+    pub font_size: u16,
+    pub laser_width: u32,
+    // レーザーを破線で描く
+    pub laser_dashed: bool,
+    // ものの周りに描く枠の色。なければ描かない
+    pub outline: Option<Rgb>,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            name: "classic".to_string(),
+            background: Rgb(32, 32, 32),
+            grid: Rgb(44, 44, 44),
+            hud: Rgb(0, 0, 0),
+            text: Rgb(224, 224, 224),
+            players: [Rgb(255, 255, 255), Rgb(128, 255, 255)],
+            robot: Rgb(255, 128, 128),
+            robot_frozen: Rgb(128, 160, 255),
+            robot_remembered: Rgb(128, 64, 64),
+            junk: Rgb(128, 128, 128),
+            cannon: Rgb(255, 255, 255),
+            laser: Rgb(255, 255, 128),
+            explosion: Rgb(255, 160, 64),
+            game_over: Rgb(255, 0, 0),
+            spark: Rgb(255, 224, 96),
+            debris: Rgb(160, 160, 160),
+            scorch: Rgb(160, 64, 32),
+            win: vec![
+                Rgb(255, 128, 128),
+                Rgb(128, 255, 128),
+                Rgb(128, 128, 255),
+                Rgb(255, 255, 128),
+                Rgb(128, 255, 255),
+                Rgb(255, 128, 255),
+            ],
+            toast: Rgb(0, 0, 64),
+            toast_title: Rgb(255, 255, 128),
+            toast_text: Rgb(224, 224, 224),
+            items: [
+                Rgb(255, 255, 64),
+                Rgb(64, 128, 255),
+                Rgb(192, 128, 64),
+                Rgb(160, 240, 255),
+                Rgb(255, 96, 255),
+            ],
+            player_look: Look::Sprite("hito.bmp".to_string()),
+            robot_look: Look::Cell,
+            junk_look: Look::Cell,
+            cannon_look: Look::Sprite("laser_cannon.bmp".to_string()),
+            item_look: Look::Cell,
+            font: "boxfont2.ttf".to_string(),
+            font_size: 24,
+            laser_width: 4,
+            laser_dashed: false,
+            outline: None,
+        }
+    }
+}
+
+impl Theme {
+    // sourceはエラーに出すファイル名
+    pub fn parse(source: &str, text: &str) -> Result<Theme, String> {
+        let mut theme = Theme {
+            name: Path::new(source)
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or(source)
+                .to_string(),
+            ..Theme::default()
+        };
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let result = match line.split_once('=') {
+                Some((key, value)) => theme.set(key.trim(), value.trim()),
+                None => Err(format!("expected key = value: {}", line)),
+            };
+            result.map_err(|e| format!("theme {}:{}: {}", source, i + 1, e))?;
+        }
+        Ok(theme)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let color = || value.parse::<Rgb>().map_err(|e| format!("{}: {}", key, e));
+        let look = |letters| Look::parse(value, letters).map_err(|e| format!("{}: {}", key, e));
+        if let Some(kind) = key.strip_prefix("item_").and_then(item_kind) {
+            let index = ITEM_KINDS.iter().position(|k| *k == kind).unwrap();
+            self.items[index] = color()?;
+            return Ok(());
+        }
+        match key {
+            "name" if !value.is_empty() => self.name = value.to_string(),
+            "background" => self.background = color()?,
+            "grid" => self.grid = color()?,
+            "hud" => self.hud = color()?,
+            "text" => self.text = color()?,
+            "player1" => self.players[0] = color()?,
+            "player2" => self.players[1] = color()?,
+            "robot" => self.robot = color()?,
+            "robot_frozen" => self.robot_frozen = color()?,
+            "robot_remembered" => self.robot_remembered = color()?,
+            "junk" => self.junk = color()?,
+            "cannon" => self.cannon = color()?,
+            "laser" => self.laser = color()?,
+            "explosion" => self.explosion = color()?,
+            "game_over" => self.game_over = color()?,
+            "spark" => self.spark = color()?,
+            "debris" => self.debris = color()?,
+            "scorch" => self.scorch = color()?,
+            "win" => {
+                self.win = value
+                    .split_whitespace()
+                    .map(|c| c.parse::<Rgb>().map_err(|e| format!("{}: {}", key, e)))
+                    .collect::<Result<_, _>>()?;
+                if self.win.is_empty() {
+                    return Err("win needs at least one color".to_string());
+                }
+            }
+            "toast" => self.toast = color()?,
+            "toast_title" => self.toast_title = color()?,
+            "toast_text" => self.toast_text = color()?,
+            "player_look" => self.player_look = look(false)?,
+            "robot_look" => self.robot_look = look(false)?,
+            "junk_look" => self.junk_look = look(false)?,
+            "cannon_look" => self.cannon_look = look(false)?,
+            "item_look" => self.item_look = look(true)?,
+            "font" if !value.is_empty() => self.font = value.to_string(),
+            "font_size" => {
+                self.font_size = value
+                    .parse()
+                    .ok()
+                    .filter(|s| (FONT_SIZE_MIN..=FONT_SIZE_MAX).contains(s))
+                    .ok_or_else(|| {
+                        format!(
+                            "font_size must be between {} and {}: {}",
+                            FONT_SIZE_MIN, FONT_SIZE_MAX, value
+                        )
+                    })?
+            }
+            "laser_width" => {
+                self.laser_width = value
+                    .parse()
+                    .ok()
+                    .filter(|w| (1..=LASER_WIDTH_MAX).contains(w))
+                    .ok_or_else(|| {
+                        format!(
+                            "laser_width must be between 1 and {}: {}",
+                            LASER_WIDTH_MAX, value
+                        )
+                    })?
+            }
+            "laser_look" => {
+                self.laser_dashed = match value {
+                    "solid" => false,
+                    "dashed" => true,
+                    _ => return Err(format!("laser_look must be solid or dashed: {}", value)),
+                }
+            }
+            "outline" if value == "none" => self.outline = None,
+            "outline" => self.outline = Some(color()?),
+            "name" | "font" => return Err(format!("{} must not be empty", key)),
+            _ => return Err(format!("unknown key: {}", key)),
+        }
+        Ok(())
+    }
+
+    pub fn item(&self, kind: ItemKind) -> Rgb {
+        self.items[ITEM_KINDS.iter().position(|k| *k == kind).unwrap()]
+    }
+
+    // 画像のファイル名。存在するかどうかは画面側で確かめる
+    pub fn sprites(&self) -> Vec<&str> {
+        [
+            &self.player_look,
+            &self.robot_look,
+            &self.junk_look,
+            &self.cannon_look,
+        ]
+        .into_iter()
+        .filter_map(|look| match look {
+            Look::Sprite(file) => Some(file.as_str()),
+            _ => None,
+        })
+        .collect()
+    }
+
+    // 文字で描くものに使う文字
+    pub fn glyphs(&self) -> Vec<char> {
+        let mut glyphs: Vec<char> = [
+            &self.player_look,
+            &self.robot_look,
+            &self.junk_look,
+            &self.cannon_look,
+            &self.item_look,
+        ]
+        .into_iter()
+        .filter_map(|look| match look {
+            Look::Glyph(c) => Some(*c),
+            _ => None,
+        })
+        .collect();
+        if self.item_look == Look::Letters {
+            glyphs.extend(ITEM_KINDS.iter().map(|k| k.letter()));
+        }
+        glyphs
+    }
+}
+
+// "emp"や"junk"からItemKindを探す。キーはItemKind::nameの小文字
+fn item_kind(name: &str) -> Option<ItemKind> {
+    ITEM_KINDS
+        .iter()
+        .copied()
+        .find(|k| k.name().to_lowercase() == name)
+}
+
+// 組み込みのテーマとユーザーのテーマ。おかしいテーマはエラーを返して飛ばす
+pub fn load_all() -> (Vec<Theme>, Vec<String>) {
+    let mut themes = Vec::new();
+    let mut errors = Vec::new();
+    for (name, text) in BUILTIN_THEMES {
+        match Theme::parse(&format!("{}.theme", name), text) {
+            Ok(theme) => themes.push(theme),
+            Err(e) => errors.push(e),
+        }
+    }
+    let dir = storage::data_dir().join("themes");
+    let mut paths: Vec<_> = fs::read_dir(&dir)
+        .map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+        .unwrap_or_default();
+    paths.sort();
+    for path in paths {
+        if path.extension().is_some_and(|e| e == "theme") {
+            match load_file(&path) {
+                Ok(theme) => themes.push(theme),
+                Err(e) => errors.push(e),
+            }
+        }
+    }
+    (themes, errors)
+}
+
+pub fn load_file(path: &Path) -> Result<Theme, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Theme::parse(&path.display().to_string(), &text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        Theme::parse("my.theme", text).unwrap_err()
+    }
+
+    #[test]
+    fn parse_keeps_classic_for_missing_keys() {
+        let theme =
+            Theme::parse("my.theme", "# comment\n\nrobot = #00ff00\nwin = #010203\n").unwrap();
+        assert_eq!(theme.name, "my");
+        assert_eq!(theme.robot, Rgb(0, 255, 0));
+        assert_eq!(theme.win, vec![Rgb(1, 2, 3)]);
+        assert_eq!(theme.junk, Theme::default().junk);
+    }
+
+    #[test]
+    fn builtin_themes_parse() {
+        for (name, text) in BUILTIN_THEMES {
+            let theme = Theme::parse(name, text).unwrap();
+            assert_eq!(theme.name, name);
+        }
+    }
+
+    #[test]
+    fn parse_errors_name_the_file_and_line() {
+        assert_eq!(
+            error("name = x\nrobot = #ff00"),
+            "theme my.theme:2: robot: invalid color: #ff00 (expected #rrggbb)"
+        );
+        assert_eq!(
+            error("robot"),
+            "theme my.theme:1: expected key = value: robot"
+        );
+        assert_eq!(
+            error("robots = #ffffff"),
+            "theme my.theme:1: unknown key: robots"
+        );
+        assert_eq!(error("font ="), "theme my.theme:1: font must not be empty");
+        assert_eq!(
+            error("win ="),
+            "theme my.theme:1: win needs at least one color"
+        );
+    }
+
+    #[test]
+    fn parse_errors_for_values() {
+        assert!(error("font_size = 100").contains("font_size must be between"));
+        assert!(error("laser_width = 0").contains("laser_width must be between"));
+        assert!(error("laser_look = dotted").contains("laser_look must be solid or dashed"));
+        assert!(error("robot_look = shape star").contains("unknown shape: star"));
+        assert!(error("robot_look = glyph ab").contains("invalid look"));
+        // lettersは道具にだけ使える
+        assert!(error("robot_look = letters").contains("invalid look"));
+        assert!(Theme::parse("my.theme", "item_look = letters").is_ok());
+    }
+}
@@ -0,0 +1,283 @@
+// 盤面のつながり方
+//
+// square : 8方向に動ける四角いマス
+// hex    : 6方向に動ける六角形のマス。奇数行を半マス右にずらして並べる(odd-r)
+//          Up と Down はなく、左右と斜め4方向だけ
+
+use crate::model::*;
+use serde::{Deserialize, Serialize};
+
+pub trait Topology {
+    // 動ける方向
+    fn directions(&self) -> &'static [Direction];
+
+    // posからdirectionに1マス進んだ位置。盤面の外に出ることもある
+    fn neighbor(&self, pos: Vec2, direction: Direction) -> Vec2;
+
+    // 何手で行けるか
+    fn distance(&self, from: Vec2, to: Vec2) -> i32;
+
+    // fromからtoに1歩近づいた位置
+    fn step_toward(&self, from: Vec2, to: Vec2) -> Vec2 {
+        self.directions()
+            .iter()
+            .map(|d| self.neighbor(from, *d))
+            .chain(std::iter::once(from))
+            .min_by_key(|p| self.distance(*p, to))
+            .unwrap_or(from)
+    }
+
+    // fromからtoまでまっすぐ進むときに通るマス。両端を含む
+    fn line(&self, from: Vec2, to: Vec2) -> Vec<Vec2>;
+
+    // 砲台の向きをこの盤面で撃てる向きにする。rightwardは盤面の左半分にあって右を向くべきとき
+    fn cannon_direction(&self, direction: Direction, rightward: bool) -> Direction;
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default, Serialize, Deserialize)]
+pub enum TopologyKind {
+    #[default]
+    Square,
+    Hex,
+}
+
+impl TopologyKind {
+    pub fn get(self) -> &'static dyn Topology {
+        match self {
+            TopologyKind::Square => &Square,
+            TopologyKind::Hex => &Hex,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TopologyKind::Square => "square",
+            TopologyKind::Hex => "hex",
+        }
+    }
+}
+
+impl std::str::FromStr for TopologyKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "square" => Ok(TopologyKind::Square),
+            "hex" => Ok(TopologyKind::Hex),
+            _ => Err(format!("unknown topology: {}", s)),
+        }
+    }
+}
+
+pub struct Square;
+
+const SQUARE_DIRECTIONS: [Direction; 8] = [
+    Direction::Left,
+    Direction::Right,
+    Direction::Down,
+    Direction::Up,
+    Direction::UpLeft,
+    Direction::UpRight,
+    Direction::DownLeft,
+    Direction::DownRight,
+];
+
+impl Topology for Square {
+    fn directions(&self) -> &'static [Direction] {
+        &SQUARE_DIRECTIONS
+    }
+
+    fn neighbor(&self, pos: Vec2, direction: Direction) -> Vec2 {
+        pos + direction.to_vec2()
+    }
+
+    fn distance(&self, from: Vec2, to: Vec2) -> i32 {
+        (to.x - from.x).abs().max((to.y - from.y).abs())
+    }
+
+    // 斜めを優先してまっすぐ近づく
+    fn step_toward(&self, from: Vec2, to: Vec2) -> Vec2 {
+        Vec2 {
+            x: from.x + (to.x - from.x).signum(),
+            y: from.y + (to.y - from.y).signum(),
+        }
+    }
+
+    // ブレゼンハムのアルゴリズム
+    fn line(&self, from: Vec2, to: Vec2) -> Vec<Vec2> {
+        let dx = (to.x - from.x).abs();
+        let dy = -(to.y - from.y).abs();
+        let (sx, sy) = ((to.x - from.x).signum(), (to.y - from.y).signum());
+        let mut err = dx + dy;
+        let mut pos = from;
+        let mut result = vec![pos];
+        while pos != to {
+            let e2 = err * 2;
+            if e2 >= dy {
+                err += dy;
+                pos.x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                pos.y += sy;
+            }
+            result.push(pos);
+        }
+        result
+    }
+
+    fn cannon_direction(&self, direction: Direction, _rightward: bool) -> Direction {
+        direction
+    }
+}
+
+pub struct Hex;
+
+const HEX_DIRECTIONS: [Direction; 6] = [
+    Direction::Left,
+    Direction::Right,
+    Direction::UpLeft,
+    Direction::UpRight,
+    Direction::DownLeft,
+    Direction::DownRight,
+];
+
+impl Hex {
+    // odd-rの座標を軸座標(q, r)にする
+    fn axial(pos: Vec2) -> (i32, i32) {
+        (pos.x - (pos.y - pos.y.rem_euclid(2)) / 2, pos.y)
+    }
+
+    fn from_axial(q: i32, r: i32) -> Vec2 {
+        Vec2 {
+            x: q + (r - r.rem_euclid(2)) / 2,
+            y: r,
+        }
+    }
+
+    // 小数の軸座標を一番近いマスに丸める
+    fn round(q: f64, r: f64) -> Vec2 {
+        let s = -q - r;
+        let (mut rq, mut rr, rs) = (q.round(), r.round(), s.round());
+        let (dq, dr, ds) = ((rq - q).abs(), (rr - r).abs(), (rs - s).abs());
+        if dq > dr && dq > ds {
+            rq = -rr - rs;
+        } else if dr > ds {
+            rr = -rq - rs;
+        }
+        Hex::from_axial(rq as i32, rr as i32)
+    }
+}
+
+impl Topology for Hex {
+    fn directions(&self) -> &'static [Direction] {
+        &HEX_DIRECTIONS
+    }
+
+    fn neighbor(&self, pos: Vec2, direction: Direction) -> Vec2 {
+        // 斜めに動くときのxの変化は行の偶奇で変わる
+        let odd = pos.y.rem_euclid(2);
+        let v = match direction {
+            Direction::Left => Vec2 { x: -1, y: 0 },
+            Direction::Right => Vec2 { x: 1, y: 0 },
+            Direction::UpLeft => Vec2 { x: odd - 1, y: -1 },
+            Direction::UpRight => Vec2 { x: odd, y: -1 },
+            Direction::DownLeft => Vec2 { x: odd - 1, y: 1 },
+            Direction::DownRight => Vec2 { x: odd, y: 1 },
+            Direction::Up | Direction::Down => Vec2 { x: 0, y: 0 },
+        };
+        pos + v
+    }
+
+    fn distance(&self, from: Vec2, to: Vec2) -> i32 {
+        let (q1, r1) = Hex::axial(from);
+        let (q2, r2) = Hex::axial(to);
+        let (dq, dr) = (q2 - q1, r2 - r1);
+        (dq.abs() + dr.abs() + (dq + dr).abs()) / 2
+    }
+
+    fn line(&self, from: Vec2, to: Vec2) -> Vec<Vec2> {
+        let n = self.distance(from, to);
+        let (q1, r1) = Hex::axial(from);
+        let (q2, r2) = Hex::axial(to);
+        (0..=n)
+            .map(|i| {
+                // ちょうどマスの境目を通るときにどちらかに寄せる
+                let t = if n == 0 { 0.0 } else { i as f64 / n as f64 };
+                Hex::round(
+                    q1 as f64 + (q2 - q1) as f64 * t + 1e-6,
+                    r1 as f64 + (r2 - r1) as f64 * t + 1e-6,
+                )
+            })
+            .collect()
+    }
+
+    fn cannon_direction(&self, direction: Direction, rightward: bool) -> Direction {
+        match (direction, rightward) {
+            (Direction::Up, true) => Direction::UpRight,
+            (Direction::Up, false) => Direction::UpLeft,
+            (Direction::Down, true) => Direction::DownRight,
+            (Direction::Down, false) => Direction::DownLeft,
+            _ => direction,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(x: i32, y: i32) -> Vec2 {
+        Vec2 { x, y }
+    }
+
+    #[test]
+    fn hex_neighbors_are_one_step_away() {
+        let hex = TopologyKind::Hex.get();
+        // 偶数行と奇数行で斜めのxの変化が違う
+        for pos in [v(5, 4), v(5, 5)] {
+            for direction in hex.directions() {
+                assert_eq!(hex.distance(pos, hex.neighbor(pos, *direction)), 1);
+            }
+        }
+        assert_eq!(hex.neighbor(v(5, 4), Direction::UpLeft), v(4, 3));
+        assert_eq!(hex.neighbor(v(5, 5), Direction::UpLeft), v(5, 4));
+    }
+
+    #[test]
+    fn hex_distance() {
+        let hex = TopologyKind::Hex.get();
+        assert_eq!(hex.distance(v(3, 3), v(3, 3)), 0);
+        assert_eq!(hex.distance(v(0, 0), v(5, 0)), 5);
+        // 斜めに進めば1手で1行とxの半分が進む
+        assert_eq!(hex.distance(v(0, 0), v(2, 4)), 4);
+        assert_eq!(hex.distance(v(0, 0), v(0, 4)), 4);
+        assert_eq!(hex.distance(v(0, 0), v(5, 4)), 7);
+        assert_eq!(hex.distance(v(2, 4), v(0, 0)), 4);
+    }
+
+    #[test]
+    fn hex_line_steps_through_neighbors() {
+        let hex = TopologyKind::Hex.get();
+        for (from, to) in [(v(0, 0), v(7, 3)), (v(6, 5), v(1, 0)), (v(2, 2), v(2, 9))] {
+            let line = hex.line(from, to);
+            assert_eq!(line.len() as i32, hex.distance(from, to) + 1);
+            assert_eq!(line[0], from);
+            assert_eq!(*line.last().unwrap(), to);
+            for pair in line.windows(2) {
+                assert_eq!(hex.distance(pair[0], pair[1]), 1);
+            }
+        }
+        assert_eq!(hex.line(v(3, 3), v(3, 3)), vec![v(3, 3)]);
+    }
+
+    #[test]
+    fn square_distance_and_line() {
+        let square = TopologyKind::Square.get();
+        assert_eq!(square.distance(v(0, 0), v(3, 5)), 5);
+        assert_eq!(
+            square.line(v(0, 0), v(3, 3)),
+            vec![v(0, 0), v(1, 1), v(2, 2), v(3, 3)]
+        );
+    }
+}